@@ -0,0 +1,334 @@
+use anyhow::{anyhow, Result};
+use crossbeam::channel::Sender;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+use toniefile::Toniefile;
+
+use crate::index::to_hex;
+use crate::taf::is_toniefile;
+use crate::ui::Action;
+use crate::{check_tag_id_validity, format_size, get_tag_id, TEMP_EXTENSION};
+
+/// Name of the manifest in a backup, it is the last file of the archive
+const MANIFEST_FILE: &str = "rustybench-backup.json";
+/// Folder in a backup that holds the files of CONTENT
+const CONTENT_DIR: &str = "CONTENT";
+
+/// What is in a backup
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupManifest {
+    /// seconds since the Unix epoch
+    pub created: u64,
+    pub files: Vec<BackupFile>,
+}
+
+/// A file in a backup
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupFile {
+    /// path relative to CONTENT, separated by `/`
+    pub path: String,
+    pub size: u64,
+    /// SHA-1 hash of the whole file as hex string
+    pub sha1: String,
+    /// tag ID, if the file is a Toniefile
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    /// audio ID from the header, if the file is a Toniefile
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_id: Option<u32>,
+}
+
+impl BackupManifest {
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|f| f.size).sum()
+    }
+}
+
+/// Hashes everything that is read through it
+struct HashingReader<R: Read> {
+    inner: R,
+    sha1: Sha1,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.sha1.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// All files below `dir`, sorted, without the temporary files of unfinished writes
+///
+/// A temporary file that is a complete Toniefile is kept, an interrupted swap leaves one of the
+/// two Toniefiles under that name. Links to folders are skipped, a link to a parent folder would
+/// never end.
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|e| {
+            let e = e?;
+            Ok((e.path(), e.file_type()?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    for (path, file_type) in entries {
+        if file_type.is_dir() {
+            collect_files(&path, files)?;
+        } else if file_type.is_symlink() && path.is_dir() {
+            warn!("skipping {}, it is a link to a folder", path.display());
+        } else if path.extension() != Some(TEMP_EXTENSION.as_ref()) {
+            files.push(path);
+        } else if is_toniefile(&path) {
+            warn!(
+                "backing up the Toniefile {} left by an unfinished swap, repair it with health",
+                path.display()
+            );
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn relative_path(content: &Path, path: &Path) -> Result<String> {
+    let relative = path.strip_prefix(content)?;
+    let parts = relative
+        .components()
+        .map(|c| {
+            c.as_os_str()
+                .to_str()
+                .ok_or(anyhow!("{} is not a valid file name", path.display()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(parts.join("/"))
+}
+
+/// Write all files of a CONTENT folder, including the system folders, to a gzipped tar archive
+///
+/// The manifest lists every file with its SHA-1 hash, Toniefiles also with tag and audio ID.
+pub fn backup(content: &Path, archive: &Path, status: &Sender<Action>) -> Result<BackupManifest> {
+    let mut paths = vec![];
+    collect_files(content, &mut paths)?;
+    let temp = archive.with_extension(TEMP_EXTENSION);
+    let result = write_backup(content, &paths, &temp, status).and_then(|manifest| {
+        fs::rename(&temp, archive)?;
+        Ok(manifest)
+    });
+    if result.is_err() {
+        // do not leave half an archive behind
+        let _ = fs::remove_file(&temp);
+        status.send(Action::CurrentFile("".to_string()))?;
+    }
+    let manifest = result?;
+    let message = format!(
+        "Backed up {} files ({}) to {}",
+        manifest.files.len(),
+        format_size(manifest.total_size()),
+        archive.display()
+    );
+    info!("{}", message);
+    status.send(Action::CurrentFile(message))?;
+    Ok(manifest)
+}
+
+fn write_backup(
+    content: &Path,
+    paths: &[PathBuf],
+    archive: &Path,
+    status: &Sender<Action>,
+) -> Result<BackupManifest> {
+    let gz = GzEncoder::new(
+        BufWriter::new(File::create(archive)?),
+        Compression::default(),
+    );
+    let mut builder = tar::Builder::new(gz);
+    let mut manifest = BackupManifest {
+        created: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs(),
+        files: vec![],
+    };
+    for (i, path) in paths.iter().enumerate() {
+        status.send(Action::CurrentFile(format!(
+            "Backing up file {} of {}: {}",
+            i + 1,
+            paths.len(),
+            path.display()
+        )))?;
+        let relative = relative_path(content, path)?;
+        let metadata = fs::metadata(path)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(metadata.len());
+        header.set_mode(0o644);
+        if let Ok(modified) = metadata.modified() {
+            header.set_mtime(
+                modified
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+            );
+        }
+        let mut reader = HashingReader {
+            inner: BufReader::new(File::open(path)?),
+            sha1: Sha1::new(),
+        };
+        builder.append_data(
+            &mut header,
+            format!("{}/{}", CONTENT_DIR, relative),
+            &mut reader,
+        )?;
+        let (tag, audio_id) = toniefile_info(path);
+        manifest.files.push(BackupFile {
+            path: relative,
+            size: metadata.len(),
+            sha1: to_hex(&reader.sha1.finalize()),
+            tag,
+            audio_id,
+        });
+    }
+    let json = serde_json::to_vec_pretty(&manifest)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(json.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(manifest.created);
+    builder.append_data(&mut header, MANIFEST_FILE, json.as_slice())?;
+    builder.into_inner()?.finish()?.into_inner()?.sync_all()?;
+    Ok(manifest)
+}
+
+/// Tag and audio ID of a Toniefile, None for other files
+fn toniefile_info(path: &Path) -> (Option<String>, Option<u32>) {
+    let Some(tag) = get_tag_id(path).filter(|tag| check_tag_id_validity(tag).is_ok()) else {
+        return (None, None);
+    };
+    match File::open(path).map(|mut f| Toniefile::parse_header(&mut f)) {
+        Ok(Ok(header)) => (Some(tag), Some(header.audio_id)),
+        _ => (None, None),
+    }
+}
+
+/// Write the files of a backup to a CONTENT folder and check their SHA-1 hashes
+///
+/// The CONTENT folder must be empty or not exist yet, unless `overwrite` is given.
+pub fn restore_backup(
+    archive: &Path,
+    content: &Path,
+    overwrite: bool,
+    status: &Sender<Action>,
+) -> Result<BackupManifest> {
+    if !overwrite && content.exists() && fs::read_dir(content)?.next().is_some() {
+        return Err(anyhow!(
+            "{} is not empty, restore the backup to a fresh card",
+            content.display()
+        ));
+    }
+    let result = extract_backup(archive, content, status).and_then(|manifest| {
+        verify_backup(content, &manifest, status)?;
+        Ok(manifest)
+    });
+    if result.is_err() {
+        status.send(Action::CurrentFile("".to_string()))?;
+    }
+    let manifest = result?;
+    let message = format!(
+        "Restored and verified {} files ({}) in {}",
+        manifest.files.len(),
+        format_size(manifest.total_size()),
+        content.display()
+    );
+    info!("{}", message);
+    status.send(Action::CurrentFile(message))?;
+    Ok(manifest)
+}
+
+fn extract_backup(
+    archive: &Path,
+    content: &Path,
+    status: &Sender<Action>,
+) -> Result<BackupManifest> {
+    let mut tar = tar::Archive::new(GzDecoder::new(BufReader::new(File::open(archive)?)));
+    let mut manifest = None;
+    fs::create_dir_all(content)?;
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if path == Path::new(MANIFEST_FILE) {
+            manifest = Some(serde_json::from_reader(&mut entry)?);
+            continue;
+        }
+        // only plain names, nothing may end up outside of CONTENT
+        let relative = path
+            .strip_prefix(CONTENT_DIR)
+            .ok()
+            .filter(|p| p.components().all(|c| matches!(c, Component::Normal(_))))
+            .ok_or(anyhow!("unexpected file {} in the backup", path.display()))?;
+        let dest = content.join(relative);
+        let kind = entry.header().entry_type();
+        if kind.is_dir() {
+            fs::create_dir_all(&dest)?;
+            continue;
+        }
+        if !kind.is_file() {
+            return Err(anyhow!("{} in the backup is no file", path.display()));
+        }
+        status.send(Action::CurrentFile(format!(
+            "Restoring file {}",
+            relative.display()
+        )))?;
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut writer = BufWriter::new(File::create(&dest)?);
+        io::copy(&mut entry, &mut writer)?;
+        writer.flush()?;
+    }
+    manifest.ok_or(anyhow!("{} has no manifest", archive.display()))
+}
+
+/// Compare the files on the card with the hashes in the manifest
+fn verify_backup(content: &Path, manifest: &BackupManifest, status: &Sender<Action>) -> Result<()> {
+    let mut failed = 0;
+    for (i, file) in manifest.files.iter().enumerate() {
+        status.send(Action::CurrentFile(format!(
+            "Verifying file {} of {}: {}",
+            i + 1,
+            manifest.files.len(),
+            file.path
+        )))?;
+        match file_sha1(&content.join(&file.path)) {
+            Ok(sha1) if sha1 == file.sha1 => {}
+            Ok(sha1) => {
+                error!("{}: SHA-1 is {}, expected {}", file.path, sha1, file.sha1);
+                failed += 1;
+            }
+            Err(e) => {
+                error!("{}: {}", file.path, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!(
+            "{} of {} restored files do not match the backup",
+            failed,
+            manifest.files.len()
+        ));
+    }
+    Ok(())
+}
+
+fn file_sha1(path: &Path) -> Result<String> {
+    let mut reader = HashingReader {
+        inner: BufReader::new(File::open(path)?),
+        sha1: Sha1::new(),
+    };
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(to_hex(&reader.sha1.finalize()))
+}
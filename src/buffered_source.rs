@@ -1,6 +1,8 @@
 use log::trace;
 use std::fs::File;
 use std::io::{BufReader, Read, Result, Seek, SeekFrom};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 use symphonia::core::io::MediaSource;
 
@@ -21,6 +23,8 @@ pub struct BufferedSource {
     is_seekable: bool,
     /// Cache the [`MediaSource::byte_len`] call
     byte_len: Option<u64>,
+    /// Current read position, shared so it can be observed after the source is boxed away
+    position: Arc<AtomicU64>,
 }
 
 impl BufferedSource {
@@ -55,6 +59,7 @@ impl BufferedSource {
             inner,
             is_seekable,
             byte_len,
+            position: Arc::new(AtomicU64::new(0)),
         }
     }
 
@@ -62,6 +67,19 @@ impl BufferedSource {
     pub fn new_default_size(file: File) -> Self {
         Self::new(file, BUF_SIZE_DEFAULT)
     }
+
+    /// Get a handle to the current read position in bytes
+    ///
+    /// Together with [`MediaSource::byte_len`] this can be used to estimate progress when the
+    /// number of frames of a stream is unknown.
+    pub fn position_handle(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.position)
+    }
+
+    fn set_position(&self, pos: u64) -> u64 {
+        self.position.store(pos, Ordering::Relaxed);
+        pos
+    }
 }
 
 impl MediaSource for BufferedSource {
@@ -76,7 +94,9 @@ impl MediaSource for BufferedSource {
 
 impl Read for BufferedSource {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        self.inner.read(buf)
+        let n = self.inner.read(buf)?;
+        self.position.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
     }
 }
 
@@ -86,7 +106,8 @@ impl Seek for BufferedSource {
         // so we are using that otherway, and fall-back to the normal Seek implementation
         if let SeekFrom::Current(v) = pos {
             self.inner.seek_relative(v)?;
-            return self.inner.stream_position();
+            let pos = self.inner.stream_position()?;
+            return Ok(self.set_position(pos));
         }
         if let SeekFrom::Start(v) = pos {
             let old_pos = self.inner.stream_position()?;
@@ -94,23 +115,27 @@ impl Seek for BufferedSource {
             if v >= old_pos {
                 let Ok(offset) = i64::try_from(v - old_pos) else {
                     // fallback, return normal seek because otherwise it would mean to doing 2 seeks, which would read unnecessary data, which may be discarded right away
-                    return self.inner.seek(pos);
+                    let pos = self.inner.seek(pos)?;
+                    return Ok(self.set_position(pos));
                 };
                 // seek forward
                 self.inner.seek_relative(offset)?;
             } else {
                 let Ok(offset) = i64::try_from(old_pos - v) else {
                     // fallback, return normal seek because otherwise it would mean to doing 2 seeks, which would read unnecessary data, which may be discarded right away
-                    return self.inner.seek(pos);
+                    let pos = self.inner.seek(pos)?;
+                    return Ok(self.set_position(pos));
                 };
                 // seek backward
                 self.inner.seek_relative(-offset)?;
             }
 
-            return self.inner.stream_position();
+            let pos = self.inner.stream_position()?;
+            return Ok(self.set_position(pos));
         }
 
         // fallback
-        self.inner.seek(pos)
+        let pos = self.inner.seek(pos)?;
+        Ok(self.set_position(pos))
     }
 }
@@ -0,0 +1,90 @@
+use anyhow::{anyhow, Result};
+use log::info;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::tonielist::Tonie;
+use crate::{format_size, populate_table};
+
+/// Number of Toniefiles listed as the largest ones on a card
+const LARGEST_COUNT: usize = 10;
+
+/// A Toniefile and the space it takes
+#[derive(Debug, Clone)]
+pub struct FileUsage {
+    pub path: PathBuf,
+    pub tag: String,
+    /// series and episode, if it is the Toniefile of a known tonie
+    pub title: Option<String>,
+    pub size: u64,
+}
+
+/// How the space of the card with a CONTENT folder is used
+#[derive(Debug, Clone)]
+pub struct CardUsage {
+    pub total: u64,
+    pub available: u64,
+    /// space taken by all Toniefiles in CONTENT
+    pub toniefiles: u64,
+    pub toniefile_count: usize,
+    /// the largest Toniefiles, largest first
+    pub largest: Vec<FileUsage>,
+}
+
+impl CardUsage {
+    pub fn used(&self) -> u64 {
+        self.total.saturating_sub(self.available)
+    }
+}
+
+/// Summarize the space on the filesystem that holds `content`
+pub fn card_usage(content: &Path, tonies: &Arc<Vec<Tonie>>) -> Result<CardUsage> {
+    let mut files = vec![];
+    populate_table(content, &mut files, tonies)?;
+    let mut usage: Vec<FileUsage> = files
+        .into_iter()
+        .map(|file| FileUsage {
+            size: fs::metadata(&file.path)
+                .map(|m| m.len())
+                .unwrap_or_default(),
+            title: file.title(),
+            path: file.path,
+            tag: file.tag,
+        })
+        .collect();
+    usage.sort_by_key(|f| std::cmp::Reverse(f.size));
+    let toniefiles = usage.iter().map(|f| f.size).sum();
+    let toniefile_count = usage.len();
+    usage.truncate(LARGEST_COUNT);
+    Ok(CardUsage {
+        total: fs2::total_space(content)?,
+        available: fs2::available_space(content)?,
+        toniefiles,
+        toniefile_count,
+        largest: usage,
+    })
+}
+
+/// Make sure there is room for `needed` more bytes on the filesystem that holds `content`
+pub fn check_free_space(content: &Path, needed: u64) -> Result<()> {
+    // CONTENT may not exist yet on a fresh card
+    let dir = content
+        .ancestors()
+        .find(|dir| dir.exists())
+        .ok_or(anyhow!("{} does not exist", content.display()))?;
+    let available = fs2::available_space(dir)?;
+    if needed > available {
+        return Err(anyhow!(
+            "not enough space on the card: about {} are needed, {} are free",
+            format_size(needed),
+            format_size(available)
+        ));
+    }
+    info!(
+        "about {} of {} free space needed",
+        format_size(needed),
+        format_size(available)
+    );
+    Ok(())
+}
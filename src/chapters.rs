@@ -0,0 +1,97 @@
+use log::debug;
+use std::time::Duration;
+
+use crate::playlist::ChapterInput;
+use crate::tags::read_tags;
+
+/// A chapter of a Toniefile as planned before encoding
+///
+/// A chapter is made of one or more inputs that are encoded back to back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chapter {
+    pub title: String,
+    /// `None` if the duration of one of the inputs is unknown
+    pub duration: Option<Duration>,
+    pub inputs: Vec<ChapterInput>,
+}
+
+fn input_duration(input: &ChapterInput, file_duration: Option<Duration>) -> Option<Duration> {
+    let start = input.start.unwrap_or_default();
+    let end = input.end.or(file_duration)?;
+    Some(end.saturating_sub(start))
+}
+
+impl Chapter {
+    /// Plan a chapter for a single input, reading title and duration from the metadata tags
+    pub fn from_input(input: ChapterInput) -> Self {
+        let tags = read_tags(&input.path).unwrap_or_else(|e| {
+            debug!("could not read tags of {}: {}", input.path.display(), e);
+            Default::default()
+        });
+        let title = input.title.clone().or(tags.title).unwrap_or_else(|| {
+            input
+                .path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string()
+        });
+        Self {
+            title,
+            duration: input_duration(&input, tags.duration),
+            inputs: vec![input],
+        }
+    }
+}
+
+/// Plan one chapter per input
+pub fn plan_chapters(inputs: Vec<ChapterInput>) -> Vec<Chapter> {
+    inputs.into_iter().map(Chapter::from_input).collect()
+}
+
+/// Number of inputs over all chapters
+pub fn count_inputs(chapters: &[Chapter]) -> usize {
+    chapters.iter().map(|c| c.inputs.len()).sum()
+}
+
+/// Total duration of all chapters, `None` if any duration is unknown
+pub fn total_duration(chapters: &[Chapter]) -> Option<Duration> {
+    chapters.iter().map(|c| c.duration).sum()
+}
+
+/// Merge the chapter at `index` with the one following it, keeping the title of the first
+pub fn merge_with_next(chapters: &mut Vec<Chapter>, index: usize) {
+    if index + 1 >= chapters.len() {
+        return;
+    }
+    let next = chapters.remove(index + 1);
+    let chapter = &mut chapters[index];
+    chapter.duration = chapter.duration.zip(next.duration).map(|(a, b)| a + b);
+    chapter.inputs.extend(next.inputs);
+}
+
+/// Split a merged chapter at `index` into one chapter per input again
+pub fn split(chapters: &mut Vec<Chapter>, index: usize) {
+    if chapters.get(index).is_none_or(|c| c.inputs.len() < 2) {
+        return;
+    }
+    let chapter = chapters.remove(index);
+    for (i, input) in chapter.inputs.into_iter().enumerate() {
+        chapters.insert(index + i, Chapter::from_input(input));
+    }
+}
+
+/// Format a duration as `h:mm:ss` or `mm:ss`
+pub fn format_duration(duration: Option<Duration>) -> String {
+    match duration {
+        Some(d) => {
+            let secs = d.as_secs();
+            if secs >= 3600 {
+                format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+            } else {
+                format!("{:02}:{:02}", secs / 60, secs % 60)
+            }
+        }
+        None => "--:--".to_string(),
+    }
+}
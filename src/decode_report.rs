@@ -0,0 +1,130 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// What to do when packets of an input file can not be decoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DecodeErrorPolicy {
+    /// skip broken packets and keep encoding
+    #[default]
+    Skip,
+    /// abort the job at the first broken packet
+    Fail,
+    /// skip broken packets, but abort the job if there are more than this many
+    FailAbove(usize),
+}
+
+impl Display for DecodeErrorPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeErrorPolicy::Skip => write!(f, "skip broken packets"),
+            DecodeErrorPolicy::Fail => write!(f, "fail on first broken packet"),
+            DecodeErrorPolicy::FailAbove(n) => write!(f, "fail above {} broken packets", n),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeErrorKind {
+    Io,
+    Decode,
+}
+
+impl Display for DecodeErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeErrorKind::Io => write!(f, "IO error"),
+            DecodeErrorKind::Decode => write!(f, "decode error"),
+        }
+    }
+}
+
+/// A single packet that was skipped during encoding
+#[derive(Debug, Clone)]
+pub struct DecodeError {
+    pub file: PathBuf,
+    /// position of the packet in the input file, if the time base of the track is known
+    pub timestamp: Option<Duration>,
+    pub kind: DecodeErrorKind,
+    pub message: String,
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let timestamp = match self.timestamp {
+            Some(t) => format!("{:02}:{:06.3}", t.as_secs() / 60, t.as_secs_f64() % 60.0),
+            None => "unknown position".to_string(),
+        };
+        write!(
+            f,
+            "{} at {}: {} ({})",
+            self.file.display(),
+            timestamp,
+            self.kind,
+            self.message
+        )
+    }
+}
+
+/// Collects all packets that were skipped during an encoding job
+#[derive(Debug, Clone, Default)]
+pub struct DecodeReport {
+    pub policy: DecodeErrorPolicy,
+    pub errors: Vec<DecodeError>,
+}
+
+impl DecodeReport {
+    pub fn new(policy: DecodeErrorPolicy) -> Self {
+        Self {
+            policy,
+            errors: vec![],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn count(&self, kind: DecodeErrorKind) -> usize {
+        self.errors.iter().filter(|e| e.kind == kind).count()
+    }
+
+    /// Record a skipped packet and check it against the policy
+    ///
+    /// Returns an error if the job has to be aborted.
+    pub fn record(&mut self, error: DecodeError) -> Result<()> {
+        log::warn!("skipped packet: {}", error);
+        self.errors.push(error);
+        match self.policy {
+            DecodeErrorPolicy::Skip => Ok(()),
+            DecodeErrorPolicy::Fail => Err(anyhow!(
+                "aborted because of a broken packet: {}",
+                self.errors[self.errors.len() - 1]
+            )),
+            DecodeErrorPolicy::FailAbove(max) if self.errors.len() > max => Err(anyhow!(
+                "aborted because more than {} packets could not be decoded\n{}",
+                max,
+                self
+            )),
+            DecodeErrorPolicy::FailAbove(_) => Ok(()),
+        }
+    }
+}
+
+impl Display for DecodeReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} packets skipped ({} IO errors, {} decode errors)",
+            self.errors.len(),
+            self.count(DecodeErrorKind::Io),
+            self.count(DecodeErrorKind::Decode)
+        )?;
+        for error in &self.errors {
+            writeln!(f, "  {}", error)?;
+        }
+        Ok(())
+    }
+}
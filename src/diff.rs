@@ -0,0 +1,184 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::sync::{compare_hashes, toniefile_hashes, valid_toniefiles, Difference};
+use crate::tonielist::Tonie;
+use crate::Teddyfile;
+
+/// A Toniefile in a [`DiffReport`]
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportFile {
+    pub tag: String,
+    /// SHA-1 hash from the header
+    pub sha1: String,
+    /// series and episode, if it is the Toniefile of a known tonie
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+}
+
+/// A tag both sides have a Toniefile for, with different audio
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangedTag {
+    pub tag: String,
+    pub left_sha1: String,
+    pub right_sha1: String,
+}
+
+/// Audio both sides have, but not under the same tags
+///
+/// The tags are all tags that have this audio on each side, also those that a sync leaves alone.
+/// A sync only moves the pairs it finds as [`Difference::Retagged`].
+#[derive(Debug, Clone, Serialize)]
+pub struct SameAudio {
+    pub sha1: String,
+    pub left_tags: Vec<String>,
+    pub right_tags: Vec<String>,
+}
+
+/// How the Toniefiles of two CONTENT folders differ, matched by tag ID and by SHA-1 hash
+///
+/// The tags only one side has and the changed tags are the [`Difference`]s of
+/// [`crate::sync::compare`], grouped by kind. Nothing is changed, see [`crate::sync`] for that.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiffReport {
+    pub left: PathBuf,
+    pub right: PathBuf,
+    /// Toniefiles at tags only the left side has, see [`Difference::OnlyLeft`]
+    pub only_left: Vec<ReportFile>,
+    /// Toniefiles at tags only the right side has, see [`Difference::OnlyRight`]
+    pub only_right: Vec<ReportFile>,
+    pub changed: Vec<ChangedTag>,
+    pub same_audio: Vec<SameAudio>,
+    /// number of tags with the same audio on both sides
+    pub identical: usize,
+}
+
+/// Compare the valid Toniefiles of two CONTENT folders
+///
+/// The Toniefiles are matched like [`crate::sync::compare`] does, so the report lists the same
+/// differences that a sync would resolve.
+pub fn diff_report(left: &Path, right: &Path, tonies: &Arc<Vec<Tonie>>) -> Result<DiffReport> {
+    let left_files = valid_toniefiles(left, tonies)?;
+    let right_files = valid_toniefiles(right, tonies)?;
+    let left_hashes = toniefile_hashes(&left_files);
+    let right_hashes = toniefile_hashes(&right_files);
+    let report_file = |files: &BTreeMap<String, Teddyfile>, tag: String, sha1: String| ReportFile {
+        title: files.get(&tag).and_then(Teddyfile::title),
+        tag,
+        sha1,
+    };
+
+    let mut report = DiffReport {
+        left: left.to_path_buf(),
+        right: right.to_path_buf(),
+        only_left: vec![],
+        only_right: vec![],
+        changed: vec![],
+        same_audio: vec![],
+        identical: left_hashes
+            .iter()
+            .filter(|(tag, sha1)| right_hashes.get(*tag) == Some(*sha1))
+            .count(),
+    };
+    for difference in compare_hashes(&left_hashes, &right_hashes) {
+        match difference {
+            Difference::OnlyLeft { tag, sha1 } => {
+                report.only_left.push(report_file(&left_files, tag, sha1))
+            }
+            Difference::OnlyRight { tag, sha1 } => {
+                report.only_right.push(report_file(&right_files, tag, sha1))
+            }
+            Difference::Changed {
+                tag,
+                left_sha1,
+                right_sha1,
+            } => report.changed.push(ChangedTag {
+                tag,
+                left_sha1,
+                right_sha1,
+            }),
+            // listed with all their tags below
+            Difference::Retagged { .. } => {}
+        }
+    }
+    let mut tags_by_hash: BTreeMap<&String, (Vec<String>, Vec<String>)> = BTreeMap::new();
+    for (tag, sha1) in &left_hashes {
+        tags_by_hash.entry(sha1).or_default().0.push(tag.clone());
+    }
+    for (tag, sha1) in &right_hashes {
+        tags_by_hash.entry(sha1).or_default().1.push(tag.clone());
+    }
+    report.same_audio = tags_by_hash
+        .into_iter()
+        .filter(|(_, (left_tags, right_tags))| {
+            !left_tags.is_empty() && !right_tags.is_empty() && left_tags != right_tags
+        })
+        .map(|(sha1, (left_tags, right_tags))| SameAudio {
+            sha1: sha1.clone(),
+            left_tags,
+            right_tags,
+        })
+        .collect();
+    Ok(report)
+}
+
+impl DiffReport {
+    pub fn is_empty(&self) -> bool {
+        self.only_left.is_empty()
+            && self.only_right.is_empty()
+            && self.changed.is_empty()
+            && self.same_audio.is_empty()
+    }
+
+    /// The report as plain text
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        let _ = writeln!(text, "left:  {}", self.left.display());
+        let _ = writeln!(text, "right: {}", self.right.display());
+        let _ = writeln!(
+            text,
+            "{} Toniefiles are the same on both sides",
+            self.identical
+        );
+        for (heading, files) in [
+            ("only on the left", &self.only_left),
+            ("only on the right", &self.only_right),
+        ] {
+            if !files.is_empty() {
+                let _ = writeln!(text, "\n{}:", heading);
+            }
+            for file in files {
+                let _ = match &file.title {
+                    Some(title) => writeln!(text, "  {} {}", file.tag, title),
+                    None => writeln!(text, "  {}", file.tag),
+                };
+            }
+        }
+        if !self.changed.is_empty() {
+            let _ = writeln!(text, "\ndifferent audio for the same tag:");
+        }
+        for changed in &self.changed {
+            let _ = writeln!(
+                text,
+                "  {} left {} right {}",
+                changed.tag, changed.left_sha1, changed.right_sha1
+            );
+        }
+        if !self.same_audio.is_empty() {
+            let _ = writeln!(text, "\nsame audio under different tags:");
+        }
+        for same in &self.same_audio {
+            let _ = writeln!(
+                text,
+                "  left {} right {}",
+                same.left_tags.join(", "),
+                same.right_tags.join(", ")
+            );
+        }
+        text
+    }
+}
@@ -0,0 +1,133 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::capacity::FileUsage;
+use crate::index::to_hex;
+use crate::tonielist::Tonie;
+use crate::{populate_table, Teddyfile};
+
+/// What the Toniefiles of a [`DuplicateGroup`] have in common
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Duplicate {
+    /// the same audio, by the SHA-1 hash in the header
+    Audio(String),
+    /// the same audio ID, but different audio
+    AudioId(u32),
+}
+
+impl Display for Duplicate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Duplicate::Audio(sha1) => write!(f, "same audio (SHA-1 {})", sha1),
+            Duplicate::AudioId(audio_id) => write!(f, "same audio ID {}", audio_id),
+        }
+    }
+}
+
+/// Toniefiles under different tags that have the same audio or the same audio ID
+#[derive(Debug, Clone)]
+pub struct DuplicateGroup {
+    pub duplicate: Duplicate,
+    pub files: Vec<FileUsage>,
+}
+
+impl DuplicateGroup {
+    /// Space that deleting all but one of the Toniefiles would free
+    pub fn wasted(&self) -> u64 {
+        match self.duplicate {
+            Duplicate::Audio(_) => {
+                let sizes = self.files.iter().map(|f| f.size);
+                sizes.clone().sum::<u64>() - sizes.max().unwrap_or_default()
+            }
+            Duplicate::AudioId(_) => 0,
+        }
+    }
+}
+
+/// Find Toniefiles with the same audio or the same audio ID on a card
+///
+/// Of the Toniefiles with the same audio only the first one is listed for their audio ID, they
+/// have the same one anyway.
+pub fn find_duplicates(content: &Path, tonies: &Arc<Vec<Tonie>>) -> Result<Vec<DuplicateGroup>> {
+    let mut files = vec![];
+    populate_table(content, &mut files, tonies)?;
+    Ok(group_duplicates(files))
+}
+
+fn group_duplicates(files: Vec<Teddyfile>) -> Vec<DuplicateGroup> {
+    let mut by_hash: BTreeMap<String, Vec<_>> = BTreeMap::new();
+    let mut by_audio_id: BTreeMap<u32, Vec<_>> = BTreeMap::new();
+    for file in files.into_iter().filter(|f| f.is_valid) {
+        let sha1 = to_hex(&file.hash);
+        let same_id = by_audio_id.entry(file.audio_id).or_default();
+        if !same_id.iter().any(|(s, _)| *s == sha1) {
+            same_id.push((sha1.clone(), file.clone()));
+        }
+        by_hash.entry(sha1).or_default().push(file);
+    }
+
+    let usage = |file: Teddyfile| FileUsage {
+        size: fs::metadata(&file.path)
+            .map(|m| m.len())
+            .unwrap_or_default(),
+        title: file.title(),
+        path: file.path,
+        tag: file.tag,
+    };
+    let mut groups: Vec<DuplicateGroup> = by_hash
+        .into_iter()
+        .filter(|(_, files)| files.len() > 1)
+        .map(|(sha1, files)| DuplicateGroup {
+            duplicate: Duplicate::Audio(sha1),
+            files: files.into_iter().map(usage).collect(),
+        })
+        .collect();
+    groups.extend(
+        by_audio_id
+            .into_iter()
+            .filter(|(_, files)| files.len() > 1)
+            .map(|(audio_id, files)| DuplicateGroup {
+                duplicate: Duplicate::AudioId(audio_id),
+                files: files.into_iter().map(|(_, file)| usage(file)).collect(),
+            }),
+    );
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn toniefile(tag: &str, hash: u8, audio_id: u32) -> Teddyfile {
+        Teddyfile::new(
+            PathBuf::from(tag),
+            true,
+            vec![hash; 20],
+            0,
+            audio_id,
+            vec![0],
+            tag.to_string(),
+            None,
+        )
+    }
+
+    #[test]
+    fn same_audio_listed_once_per_audio_id() {
+        let groups = group_duplicates(vec![
+            toniefile("E00403500A1B2C3D", 1, 7),
+            toniefile("E00403500A1B2C4D", 1, 7),
+            toniefile("E00403500A1B2C5D", 2, 7),
+        ]);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].duplicate, Duplicate::Audio(to_hex(&[1; 20])));
+        assert_eq!(groups[0].files.len(), 2);
+        assert_eq!(groups[1].duplicate, Duplicate::AudioId(7));
+        let tags: Vec<_> = groups[1].files.iter().map(|f| f.tag.as_str()).collect();
+        assert_eq!(tags, ["E00403500A1B2C3D", "E00403500A1B2C5D"]);
+    }
+}
@@ -0,0 +1,295 @@
+use anyhow::{anyhow, Result};
+use audiopus::coder::Encoder;
+use audiopus::{ffi, Application, Bitrate, Channels, SampleRate};
+use log::debug;
+use sha1::{Digest, Sha1};
+use std::io::{Cursor, Seek, SeekFrom, Write};
+use toniefile::toniehead::TonieboxAudioFileHeader;
+use toniefile::Toniefile;
+
+use crate::options::{OpusSettings, CHANNELS};
+use crate::taf::{
+    encode_header, laced_len, packet_len_for, pad_packet, OggPage, BLOCK_SIZE, FRAME_SAMPLES,
+    MAX_CHAPTERS, PAGE_EOS, PAGE_HEADER_SIZE,
+};
+
+/// Interleaved samples of one Opus frame
+const FRAME_LEN: usize = FRAME_SAMPLES * CHANNELS;
+/// Special value of libopus for a frame duration of 60 ms
+const OPUS_FRAMESIZE_60_MS: i32 = 5006;
+/// Shorter packets are padded, so a block never holds more packets than the 255 lacing values of
+/// an Ogg page can describe
+const PACKET_MIN_LEN: usize = 32;
+/// A block with less space left than this is filled up by padding the packet that is added
+const BLOCK_MIN_REST: usize = 64;
+
+/// Writes Toniefiles with the given Opus settings
+///
+/// Works like [`Toniefile`], but the bitrate and the complexity of the encoder can be chosen.
+/// Every Ogg page fills a block, so no page crosses a block boundary. Chapters start at the
+/// beginning of a block, the block before is filled up with silence.
+pub struct TafEncoder<W: Write + Seek> {
+    writer: W,
+    encoder: Encoder,
+    audio_id: u32,
+    /// samples that do not make a full frame yet
+    frame: Vec<i16>,
+    /// packets of the page that is being filled
+    packets: Vec<Vec<u8>>,
+    /// bytes of the current block in front of that page
+    block_used: usize,
+    /// blocks of audio that are filled completely
+    blocks: u32,
+    granule: u64,
+    sequence: u32,
+    track_page_nums: Vec<u32>,
+    sha1: Sha1,
+    audio_len: u64,
+    finishing: bool,
+}
+
+impl<W: Write + Seek> TafEncoder<W> {
+    pub fn new(mut writer: W, audio_id: u32, settings: OpusSettings) -> Result<Self> {
+        settings.validate()?;
+        let mut encoder = Encoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio)?;
+        encoder.set_bitrate(Bitrate::BitsPerSecond(settings.bitrate as i32 * 1000))?;
+        encoder.set_complexity(settings.complexity)?;
+        encoder.set_vbr(true)?;
+        encoder.set_encoder_ctl_request(
+            ffi::OPUS_SET_EXPERT_FRAME_DURATION_REQUEST,
+            OPUS_FRAMESIZE_60_MS,
+        )?;
+
+        // the Opus header pages are the same as in the Toniefiles of the toniefile crate
+        let mut head = Toniefile::new(Cursor::new(vec![]), audio_id, None)?
+            .writer()
+            .into_inner();
+        let head = head.split_off(BLOCK_SIZE);
+        writer.write_all(&[0; BLOCK_SIZE])?;
+        writer.write_all(&head)?;
+        let mut sha1 = Sha1::new();
+        sha1.update(&head);
+
+        Ok(Self {
+            writer,
+            encoder,
+            audio_id,
+            frame: Vec::with_capacity(FRAME_LEN),
+            packets: vec![],
+            block_used: head.len(),
+            blocks: 0,
+            granule: 0,
+            sequence: 2,
+            track_page_nums: vec![0],
+            sha1,
+            audio_len: head.len() as u64,
+            finishing: false,
+        })
+    }
+
+    /// Encode interleaved stereo samples
+    pub fn encode(&mut self, mut samples: &[i16]) -> Result<()> {
+        while !samples.is_empty() {
+            let take = (FRAME_LEN - self.frame.len()).min(samples.len());
+            self.frame.extend_from_slice(&samples[..take]);
+            samples = &samples[take..];
+            if self.frame.len() == FRAME_LEN {
+                self.encode_frame(false)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Start a new chapter at the next block
+    pub fn new_chapter(&mut self) -> Result<()> {
+        if self.track_page_nums.len() >= MAX_CHAPTERS {
+            return Err(anyhow!(
+                "a Toniefile can not have more than {} chapters",
+                MAX_CHAPTERS
+            ));
+        }
+        self.fill_block()?;
+        self.track_page_nums.push(self.blocks);
+        Ok(())
+    }
+
+    /// Write the rest of the audio and the header and return the writer
+    pub fn finalize(mut self) -> Result<W> {
+        self.finishing = true;
+        if self.frame.is_empty() && self.packets.is_empty() {
+            // the last page has already been written, add one with the end of stream mark
+            self.frame.resize(FRAME_LEN, 0);
+        }
+        self.fill_block()?;
+        let header = TonieboxAudioFileHeader {
+            sha1_hash: self.sha1.finalize().to_vec(),
+            num_bytes: self.audio_len,
+            audio_id: self.audio_id,
+            track_page_nums: self.track_page_nums,
+            fill: vec![],
+        };
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&encode_header(header)?)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    /// Complete the current frame and the current block with silence
+    fn fill_block(&mut self) -> Result<()> {
+        if !self.frame.is_empty() {
+            self.frame.resize(FRAME_LEN, 0);
+            self.encode_frame(false)?;
+        }
+        while !self.packets.is_empty() {
+            self.frame.resize(FRAME_LEN, 0);
+            self.encode_frame(true)?;
+        }
+        Ok(())
+    }
+
+    /// Bytes left in the current block for lacing values and packets of the current page
+    fn page_space(&self) -> usize {
+        let used: usize = self.packets.iter().map(|p| laced_len(p.len())).sum();
+        BLOCK_SIZE - self.block_used - PAGE_HEADER_SIZE - used
+    }
+
+    /// Encode the frame, with `close` its packet fills the block if possible
+    fn encode_frame(&mut self, close: bool) -> Result<()> {
+        let space = self.page_space();
+        let fill_len = packet_len_for(space);
+        // leave room for padding, or for one more packet if the block can not be filled by this one
+        let max_len = match fill_len {
+            Some(len) => len - 2,
+            None => (0..space - BLOCK_MIN_REST)
+                .rev()
+                .find(|&len| laced_len(len) <= space - BLOCK_MIN_REST)
+                .unwrap_or_default(),
+        };
+        let mut packet = vec![0; max_len];
+        let len = self.encoder.encode(&self.frame, &mut packet)?;
+        packet.truncate(len);
+        self.frame.clear();
+        self.granule += FRAME_SAMPLES as u64;
+
+        let rest = space.saturating_sub(laced_len(len.max(PACKET_MIN_LEN)));
+        match fill_len {
+            Some(fill_len) if close || rest < BLOCK_MIN_REST => {
+                self.packets.push(pad_packet(&packet, fill_len)?);
+                self.write_page()
+            }
+            _ => {
+                if len < PACKET_MIN_LEN {
+                    packet = pad_packet(&packet, PACKET_MIN_LEN)?;
+                }
+                self.packets.push(packet);
+                Ok(())
+            }
+        }
+    }
+
+    /// Write the packets as a page that fills the current block
+    fn write_page(&mut self) -> Result<()> {
+        let samples = self.packets.len() as u64 * FRAME_SAMPLES as u64;
+        let mut page = OggPage::with_packets(&self.packets, samples);
+        page.set_granule(self.granule);
+        page.set_serial(self.audio_id);
+        page.set_sequence(self.sequence);
+        page.set_flag(PAGE_EOS, self.finishing);
+        page.update_crc();
+        if self.block_used + page.len() != BLOCK_SIZE {
+            return Err(anyhow!(
+                "Ogg page {} does not fill its block",
+                self.sequence
+            ));
+        }
+        debug!("page {} with {} packets", self.sequence, self.packets.len());
+        self.writer.write_all(page.data())?;
+        self.sha1.update(page.data());
+        self.audio_len += page.len() as u64;
+        self.packets.clear();
+        self.block_used = 0;
+        self.blocks += 1;
+        self.sequence += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::SAMPLE_RATE;
+    use crate::taf::Taf;
+    use std::time::Duration;
+
+    /// `seconds` of a tone with the loudness changing, so the packets have different lengths
+    fn tone(seconds: f32) -> Vec<i16> {
+        let len = (seconds * SAMPLE_RATE as f32) as usize;
+        (0..len)
+            .flat_map(|n| {
+                let t = n as f32 / SAMPLE_RATE as f32;
+                let level = 2000.0 + 6000.0 * (t * 0.7).sin().abs();
+                let s = ((t * 440.0 * std::f32::consts::TAU).sin() * level) as i16;
+                [s, -s]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let chapters = [4.0, 0.1, 2.5];
+        let mut encoder =
+            TafEncoder::new(Cursor::new(vec![]), 0x1234, OpusSettings::default()).unwrap();
+        for (i, &seconds) in chapters.iter().enumerate() {
+            if i > 0 {
+                encoder.new_chapter().unwrap();
+            }
+            // in uneven pieces, like from the decoder
+            for piece in tone(seconds).chunks(1234) {
+                encoder.encode(piece).unwrap();
+            }
+        }
+        let data = encoder.finalize().unwrap().into_inner();
+        assert_eq!(data.len() % BLOCK_SIZE, 0);
+
+        // every audio page fills its block, the first one the rest after the Opus header pages
+        let mut pos = BLOCK_SIZE;
+        let mut granule = 0;
+        let mut pages = 0;
+        while pos < data.len() {
+            let page = OggPage::parse(&data[pos..]).unwrap();
+            pos += page.len();
+            if page.granule() == 0 {
+                assert!(pos < 2 * BLOCK_SIZE, "header page after the first block");
+                continue;
+            }
+            assert_eq!(
+                pos % BLOCK_SIZE,
+                0,
+                "page {} does not fill its block",
+                pages
+            );
+            assert!(
+                page.granule() > granule,
+                "granule of page {} decreases",
+                pages
+            );
+            granule = page.granule();
+            pages += 1;
+        }
+
+        let taf = Taf::from_bytes(&data).unwrap();
+        assert_eq!(taf.header.audio_id, 0x1234);
+        assert_eq!(taf.header.num_bytes as usize, data.len() - BLOCK_SIZE);
+        assert_eq!(taf.header.track_page_nums.len(), chapters.len());
+        assert_eq!(taf.header.track_page_nums[0], 0);
+        assert!(*taf.header.track_page_nums.last().unwrap() < pages);
+        assert_eq!(taf.samples(), granule);
+        // every chapter starts in a new block, the one before is filled up with less than a
+        // second of silence
+        for (chapter, &seconds) in taf.chapters().iter().zip(&chapters) {
+            let seconds = Duration::from_secs_f32(seconds);
+            assert!(chapter.duration() >= seconds);
+            assert!(chapter.duration() < seconds + Duration::from_secs(1));
+        }
+    }
+}
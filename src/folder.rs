@@ -0,0 +1,182 @@
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+
+use crate::playlist::ChapterInput;
+use crate::tags::{read_tags, AudioTags};
+
+/// File extensions of the audio formats symphonia can decode
+const AUDIO_EXTENSIONS: [&str; 14] = [
+    "mp3", "mp2", "mp1", "flac", "wav", "ogg", "oga", "m4a", "m4b", "mp4", "aac", "mka", "mkv",
+    "webm",
+];
+
+pub fn is_audio_file(path: &Path) -> bool {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase())
+        .is_some_and(|e| AUDIO_EXTENSIONS.contains(&e.as_str()))
+}
+
+/// Compare two strings the way a human would, so "track 2" comes before "track 10"
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let mut x_num = String::new();
+                while let Some(c) = a.next_if(|c| c.is_ascii_digit()) {
+                    x_num.push(c);
+                }
+                let mut y_num = String::new();
+                while let Some(c) = b.next_if(|c| c.is_ascii_digit()) {
+                    y_num.push(c);
+                }
+                // compare by value without parsing, numbers can be longer than any integer type
+                let x_trim = x_num.trim_start_matches('0');
+                let y_trim = y_num.trim_start_matches('0');
+                let ord = x_trim
+                    .len()
+                    .cmp(&y_trim.len())
+                    .then_with(|| x_trim.cmp(y_trim))
+                    .then_with(|| x_num.len().cmp(&y_num.len()));
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ord = x.to_lowercase().cmp(y.to_lowercase());
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+fn collect_audio_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in dir.read_dir()?.flatten() {
+        let path = entry.path();
+        // links to folders are not followed, a link to a parent folder would never end
+        if entry.file_type()?.is_dir() {
+            collect_audio_files(&path, files)?;
+        } else if is_audio_file(&path) {
+            files.push(path);
+        } else {
+            debug!("skipping {}, not a supported audio file", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Recursively collect the supported audio files of a folder as chapters
+///
+/// Subfolders are ordered by their names. Within a folder the files are ordered by their disc and
+/// track number tags if every file has one, otherwise by their file names.
+pub fn collect_folder(dir: &Path) -> Result<Vec<ChapterInput>> {
+    info!("collecting audio files in {}", dir.display());
+    let mut files = vec![];
+    collect_audio_files(dir, &mut files)?;
+    if files.is_empty() {
+        return Err(anyhow!(
+            "no supported audio files found in {}",
+            dir.display()
+        ));
+    }
+
+    let mut dirs: Vec<PathBuf> = files
+        .iter()
+        .filter_map(|f| f.parent().map(Path::to_path_buf))
+        .collect();
+    dirs.sort_by(|a, b| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()));
+    dirs.dedup();
+
+    let mut chapters = vec![];
+    for dir in dirs {
+        let mut group: Vec<(PathBuf, AudioTags)> = files
+            .iter()
+            .filter(|f| f.parent() == Some(dir.as_path()))
+            .map(|f| (f.clone(), read_tags(f).unwrap_or_default()))
+            .collect();
+        let by_name = |a: &(PathBuf, AudioTags), b: &(PathBuf, AudioTags)| {
+            natural_cmp(&a.0.to_string_lossy(), &b.0.to_string_lossy())
+        };
+        if group.iter().all(|(_, tags)| tags.track_number.is_some()) {
+            debug!("ordering {} by track numbers", dir.display());
+            group.sort_by(|a, b| {
+                (a.1.disc_number.unwrap_or_default(), a.1.track_number)
+                    .cmp(&(b.1.disc_number.unwrap_or_default(), b.1.track_number))
+                    .then_with(|| by_name(a, b))
+            });
+        } else {
+            debug!("ordering {} by file names", dir.display());
+            group.sort_by(by_name);
+        }
+        chapters.extend(group.into_iter().map(|(path, tags)| ChapterInput {
+            title: tags.title,
+            ..ChapterInput::whole_file(path)
+        }));
+    }
+    Ok(chapters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn numbers_by_value() {
+        assert_eq!(natural_cmp("track2", "track10"), Ordering::Less);
+        assert_eq!(natural_cmp("track10", "track2"), Ordering::Greater);
+        assert_eq!(natural_cmp("track 9.mp3", "track 10.mp3"), Ordering::Less);
+        assert_eq!(natural_cmp("2 b", "10 a"), Ordering::Less);
+        // leading zeros only break ties
+        assert_eq!(natural_cmp("track02", "track2"), Ordering::Greater);
+        assert_eq!(natural_cmp("track002", "track10"), Ordering::Less);
+        assert_eq!(
+            natural_cmp("part 123456789012345678901234567890", "part 99"),
+            Ordering::Greater
+        );
+    }
+
+    #[test]
+    fn mixed_case() {
+        assert_eq!(natural_cmp("Track2", "track10"), Ordering::Less);
+        assert_eq!(natural_cmp("apple", "Banana"), Ordering::Less);
+        assert_eq!(natural_cmp("CD1", "cd1"), Ordering::Equal);
+        assert_eq!(natural_cmp("track", "track1"), Ordering::Less);
+    }
+
+    #[test]
+    fn folder_order() {
+        let dir = std::env::temp_dir().join(format!("rusty_bench_folder_{}", std::process::id()));
+        fs::create_dir_all(dir.join("CD2")).unwrap();
+        fs::create_dir_all(dir.join("CD10")).unwrap();
+        for file in [
+            "CD2/Track10.mp3",
+            "CD2/track2.mp3",
+            "CD2/cover.jpg",
+            "CD10/track1.mp3",
+        ] {
+            fs::write(dir.join(file), b"").unwrap();
+        }
+        let chapters = collect_folder(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let paths: Vec<_> = chapters.unwrap().into_iter().map(|c| c.path).collect();
+        assert_eq!(
+            paths,
+            [
+                dir.join("CD2/track2.mp3"),
+                dir.join("CD2/Track10.mp3"),
+                dir.join("CD10/track1.mp3"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,240 @@
+use anyhow::{anyhow, Result};
+use log::{error, info};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::taf::is_toniefile;
+use crate::trash::move_to_trash;
+use crate::{remove_dir_if_empty, SYSTEM_DIR_PREFIX, TEMP_EXTENSION};
+
+/// What is wrong with a file or folder in CONTENT
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Problem {
+    /// a tag folder without any files
+    EmptyDir,
+    /// a folder whose name is not 8 uppercase hex digits
+    BadDirName,
+    /// a file in a tag folder that is no Toniefile or has no valid name, or a file directly in
+    /// CONTENT
+    StrayFile,
+    /// a folder inside a tag folder, the Toniebox only looks one level deep
+    NestedDir,
+    /// the temporary file of an encoding or swap that did not finish
+    TempLeftover,
+}
+
+impl Problem {
+    pub const ALL: [Problem; 5] = [
+        Problem::EmptyDir,
+        Problem::BadDirName,
+        Problem::StrayFile,
+        Problem::NestedDir,
+        Problem::TempLeftover,
+    ];
+
+    /// What [`repair`] does about the problem
+    pub fn repair_description(self) -> &'static str {
+        match self {
+            Problem::EmptyDir => "remove the empty folders",
+            Problem::BadDirName => {
+                "rename folders with lowercase hex digits, move the files of the others to the trash"
+            }
+            Problem::StrayFile => {
+                "rename Toniefiles with lowercase hex digits, move the other files to the trash"
+            }
+            Problem::NestedDir => "move the files of the folders to the trash",
+            Problem::TempLeftover => {
+                "move the Toniefiles left by a swap to the trash, delete the other temporary files"
+            }
+        }
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::EmptyDir => write!(f, "empty tag folders"),
+            Problem::BadDirName => write!(f, "folders that are no tag folders"),
+            Problem::StrayFile => write!(f, "files that are no Toniefiles"),
+            Problem::NestedDir => write!(f, "folders inside tag folders"),
+            Problem::TempLeftover => write!(f, "leftovers of unfinished writes"),
+        }
+    }
+}
+
+/// A file or folder in CONTENT that the Toniebox can not use
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub problem: Problem,
+    pub path: PathBuf,
+}
+
+fn is_hex_name(name: &str) -> bool {
+    name.len() == 8 && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn is_tag_name(name: &str) -> bool {
+    is_hex_name(name) && !name.chars().any(|c| c.is_ascii_lowercase())
+}
+
+fn is_temp(path: &Path) -> bool {
+    path.extension() == Some(TEMP_EXTENSION.as_ref())
+}
+
+/// Look for everything in a CONTENT folder that is not a Toniefile in a tag folder
+///
+/// The system folders starting with `000000` are not checked. The contents of folders that
+/// are reported are not listed again.
+pub fn check_health(content: &Path) -> Result<Vec<Finding>> {
+    let mut findings = vec![];
+    let mut found = |problem, path| findings.push(Finding { problem, path });
+    let mut entries = fs::read_dir(content)?
+        .map(|e| Ok(e?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if !path.is_dir() {
+            if is_temp(&path) {
+                found(Problem::TempLeftover, path);
+            } else {
+                found(Problem::StrayFile, path);
+            }
+            continue;
+        }
+        if name.starts_with(SYSTEM_DIR_PREFIX) {
+            continue;
+        }
+        if !is_tag_name(&name) {
+            found(Problem::BadDirName, path);
+            continue;
+        }
+        let mut files = fs::read_dir(&path)?
+            .map(|e| Ok(e?.path()))
+            .collect::<Result<Vec<_>>>()?;
+        if files.is_empty() {
+            found(Problem::EmptyDir, path);
+            continue;
+        }
+        files.sort();
+        for file in files {
+            let name = file.file_name().unwrap_or_default().to_string_lossy();
+            if file.is_dir() {
+                found(Problem::NestedDir, file);
+            } else if is_temp(&file) {
+                found(Problem::TempLeftover, file);
+            } else if !is_tag_name(&name) || !is_toniefile(&file) {
+                found(Problem::StrayFile, file);
+            }
+        }
+    }
+    findings.sort_by_key(|f| f.problem);
+    Ok(findings)
+}
+
+/// Fix a finding of [`check_health`]
+///
+/// Nothing is deleted for good except empty folders and partly written temporary files, everything
+/// else goes to the trash. A swap that failed may leave the only copy of a Toniefile as temporary
+/// file. Temporary files must not be repaired while a Toniefile is encoded.
+pub fn repair(content: &Path, finding: &Finding) -> Result<()> {
+    let path = &finding.path;
+    match finding.problem {
+        Problem::EmptyDir => fs::remove_dir(path)?,
+        Problem::BadDirName => {
+            if !rename_uppercase(path)? {
+                trash_dir_contents(content, path)?;
+            }
+        }
+        Problem::StrayFile => {
+            if !(is_toniefile(path) && rename_uppercase(path)?) {
+                move_to_trash(content, path)?;
+                remove_parent_if_empty(content, path)?;
+            }
+        }
+        Problem::NestedDir => {
+            trash_dir_contents(content, path)?;
+            remove_parent_if_empty(content, path)?;
+        }
+        Problem::TempLeftover => {
+            if is_toniefile(path) {
+                move_to_trash(content, path)?;
+            } else {
+                fs::remove_file(path)?;
+                info!("deleted {}", path.display());
+            }
+            remove_parent_if_empty(content, path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Repair all findings with one of `problems`, the ones that fail are logged and skipped
+pub fn repair_all(content: &Path, findings: &[Finding], problems: &[Problem]) -> Result<()> {
+    let findings: Vec<_> = findings
+        .iter()
+        .filter(|f| problems.contains(&f.problem))
+        .collect();
+    let mut failed = 0;
+    for finding in &findings {
+        if let Err(e) = repair(content, finding) {
+            error!("{}: {}", finding.path.display(), e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!(
+            "{} of {} problems could not be repaired",
+            failed,
+            findings.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Give a file or folder named with lowercase hex digits the uppercase name
+///
+/// Returns false if the name is no hex number or the uppercase name is taken. The names in the
+/// folder are compared, because `exists` is true for any case on FAT formatted cards.
+fn rename_uppercase(path: &Path) -> Result<bool> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let upper = name.to_ascii_uppercase();
+    let parent = path
+        .parent()
+        .ok_or(anyhow!("{} has no parent folder", path.display()))?;
+    if !is_hex_name(&name) {
+        return Ok(false);
+    }
+    for entry in fs::read_dir(parent)? {
+        if entry?.file_name().to_string_lossy() == upper {
+            return Ok(false);
+        }
+    }
+    let dest = path.with_file_name(upper);
+    fs::rename(path, &dest)?;
+    info!("renamed {} to {}", path.display(), dest.display());
+    Ok(true)
+}
+
+/// Move all files below a folder to the trash, then remove the folder
+fn trash_dir_contents(content: &Path, dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            trash_dir_contents(content, &path)?;
+        } else {
+            move_to_trash(content, &path)?;
+        }
+    }
+    fs::remove_dir(dir)?;
+    Ok(())
+}
+
+/// Remove the folder of a repaired file, unless it is CONTENT itself
+fn remove_parent_if_empty(content: &Path, path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(parent) if parent != content => remove_dir_if_empty(parent),
+        _ => Ok(()),
+    }
+}
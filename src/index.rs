@@ -0,0 +1,168 @@
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use toniefile::Toniefile;
+
+use crate::chapters::Chapter;
+use crate::options::{EncodeOptions, OpusSettings};
+use crate::tag_path;
+
+/// Name of the folder next to CONTENT where RustyBench keeps its own data
+const SIDECAR_DIR: &str = ".rustybench";
+const INDEX_FILE: &str = "index.json";
+
+/// Folder for RustyBench's own data belonging to a CONTENT folder
+///
+/// It lives next to CONTENT, so it is on the same card but the Toniebox never looks at it.
+pub fn sidecar_dir(content: &Path) -> Result<PathBuf> {
+    let parent = content
+        .parent()
+        .ok_or(anyhow!("{} has no parent folder", content.display()))?;
+    Ok(parent.join(SIDECAR_DIR))
+}
+
+/// What a Toniefile was built from, as recorded after a build
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    /// see [`fingerprint`]
+    pub fingerprint: String,
+    /// the SHA-1 hash from the header of the Toniefile that was built
+    pub sha1: String,
+}
+
+/// Index of the Toniefiles built from a manifest, by tag ID
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BuildIndex {
+    entries: BTreeMap<String, IndexEntry>,
+}
+
+impl BuildIndex {
+    /// Load the index of a CONTENT folder, an empty index if there is none yet
+    pub fn load(content: &Path) -> Result<Self> {
+        let path = sidecar_dir(content)?.join(INDEX_FILE);
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+        Ok(serde_json::from_reader(File::open(path)?)?)
+    }
+
+    pub fn save(&self, content: &Path) -> Result<()> {
+        let dir = sidecar_dir(content)?;
+        fs::create_dir_all(&dir)?;
+        // write a new index and replace the old one, a crash must not leave half an index behind
+        let path = dir.join(INDEX_FILE);
+        let temp = path.with_extension("tmp");
+        serde_json::to_writer_pretty(File::create(&temp)?, self)?;
+        fs::rename(temp, path)?;
+        Ok(())
+    }
+
+    pub fn get(&self, tag: &str) -> Option<&IndexEntry> {
+        self.entries.get(tag)
+    }
+
+    pub fn insert(&mut self, tag: &str, entry: IndexEntry) {
+        self.entries.insert(tag.to_string(), entry);
+    }
+
+    /// Whether the Toniefile for `tag` was built from inputs with this fingerprint and has not
+    /// been replaced since
+    pub fn is_unchanged(&self, content: &Path, tag: &str, fingerprint: &str) -> bool {
+        let Some(entry) = self.get(tag) else {
+            return false;
+        };
+        if entry.fingerprint != fingerprint {
+            debug!("{}: inputs or options changed", tag);
+            return false;
+        }
+        match tag_path(content, tag).and_then(|path| header_hash(&path)) {
+            Ok(sha1) if sha1 == entry.sha1 => true,
+            Ok(_) => {
+                debug!("{}: the Toniefile on the card was replaced", tag);
+                false
+            }
+            Err(e) => {
+                debug!("{}: could not read the Toniefile: {}", tag, e);
+                false
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct InputFingerprint<'a> {
+    path: &'a Path,
+    start: Option<Duration>,
+    end: Option<Duration>,
+    size: Option<u64>,
+    modified: Option<SystemTime>,
+}
+
+#[derive(Serialize)]
+struct BuildFingerprint<'a> {
+    chapters: Vec<Vec<InputFingerprint<'a>>>,
+    gap: Duration,
+    normalize: bool,
+    trim_silence: bool,
+    opus: OpusSettings,
+}
+
+/// A hash over everything that ends up in the audio of a Toniefile
+///
+/// The input files are identified by path, size and modification time, the chapter titles are
+/// left out because they are not stored in the Toniefile.
+pub fn fingerprint(chapters: &[Chapter], options: &EncodeOptions) -> Result<String> {
+    let chapters = chapters
+        .iter()
+        .map(|chapter| {
+            chapter
+                .inputs
+                .iter()
+                .map(|input| {
+                    let metadata = fs::metadata(&input.path).ok();
+                    InputFingerprint {
+                        path: &input.path,
+                        start: input.start,
+                        end: input.end,
+                        size: metadata.as_ref().map(|m| m.len()),
+                        modified: metadata.and_then(|m| m.modified().ok()),
+                    }
+                })
+                .collect()
+        })
+        .collect();
+    let fingerprint = BuildFingerprint {
+        chapters,
+        gap: options.gap,
+        normalize: options.normalize,
+        trim_silence: options.trim_silence,
+        opus: options.opus,
+    };
+    let json = serde_json::to_vec(&fingerprint)?;
+    Ok(to_hex(&Sha1::digest(json)))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The SHA-1 hash from the header of a Toniefile as hex string
+pub fn header_hash(path: &Path) -> Result<String> {
+    let header = Toniefile::parse_header(&mut File::open(path)?)?;
+    Ok(to_hex(&header.sha1_hash))
+}
+
+/// Record in the index of `content` that the Toniefile for `tag` was built with `fingerprint`
+pub fn record_build(content: &Path, tag: &str, fingerprint: String) -> Result<()> {
+    let tag = tag.to_ascii_uppercase();
+    let sha1 = header_hash(&tag_path(content, &tag)?)?;
+    info!("recording {} in the build index", tag);
+    let mut index = BuildIndex::load(content)?;
+    index.insert(&tag, IndexEntry { fingerprint, sha1 });
+    index.save(content)
+}
@@ -0,0 +1,299 @@
+use anyhow::Result;
+use crossbeam::channel::Sender;
+use log::{error, info};
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use crate::chapters::{count_inputs, Chapter};
+use crate::decode_report::DecodeReport;
+use crate::index::record_build;
+use crate::options::EncodeOptions;
+use crate::progress::Progress;
+use crate::tonielist::Tonie;
+use crate::ui::Action;
+use crate::{add_audio_file, append_chapters};
+
+pub type JobId = u64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed(String),
+    Cancelled,
+}
+
+impl JobState {
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            JobState::Done | JobState::Failed(_) | JobState::Cancelled
+        )
+    }
+}
+
+impl Display for JobState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobState::Queued => write!(f, "queued"),
+            JobState::Running => write!(f, "running"),
+            JobState::Done => write!(f, "done"),
+            JobState::Failed(e) => write!(f, "failed: {}", e),
+            JobState::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+/// Messages a running job sends to the GUI
+#[derive(Debug, Clone)]
+pub enum JobEvent {
+    Progress(Progress),
+    CurrentFileNo(usize),
+}
+
+/// Returned by a job that noticed it was cancelled
+#[derive(Debug)]
+pub struct JobCancelled;
+
+impl Display for JobCancelled {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "job was cancelled")
+    }
+}
+
+impl std::error::Error for JobCancelled {}
+
+/// Everything a job needs to talk to the outside world
+#[derive(Debug, Clone)]
+pub struct JobContext {
+    pub id: JobId,
+    tx: Sender<Action>,
+    cancel: Arc<AtomicBool>,
+}
+
+impl JobContext {
+    pub fn new(id: JobId, tx: Sender<Action>) -> Self {
+        Self {
+            id,
+            tx,
+            cancel: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn send(&self, event: JobEvent) -> Result<()> {
+        self.tx.send(Action::Job(self.id, event))?;
+        Ok(())
+    }
+
+    /// Returns [`JobCancelled`] as error if the job should stop
+    pub fn check_cancelled(&self) -> Result<()> {
+        if self.cancel.load(Ordering::Relaxed) {
+            return Err(JobCancelled.into());
+        }
+        Ok(())
+    }
+}
+
+/// Where the chapters of a job end up
+#[derive(Debug, Clone)]
+pub enum JobTarget {
+    /// a new Toniefile for a tag in a CONTENT folder
+    Tag { content: PathBuf, tag: String },
+    /// the end of an existing Toniefile
+    Append(PathBuf),
+}
+
+impl Display for JobTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobTarget::Tag { tag, .. } => write!(f, "Tag {}", tag),
+            JobTarget::Append(path) => write!(f, "Append to {}", path.display()),
+        }
+    }
+}
+
+/// Chapters to be encoded
+#[derive(Debug, Clone)]
+pub struct JobRequest {
+    pub target: JobTarget,
+    pub chapters: Vec<Chapter>,
+    pub options: EncodeOptions,
+    /// recorded in the build index when the job is done, see [`crate::index`]
+    pub fingerprint: Option<String>,
+    /// official tonies, whose audio IDs a new Toniefile must not get
+    pub tonies: Arc<Vec<Tonie>>,
+}
+
+/// Encode the chapters of a request
+pub fn run_request(request: JobRequest, job: JobContext) -> Result<DecodeReport> {
+    match request.target {
+        JobTarget::Tag { content, tag } => {
+            let report = add_audio_file(
+                content.clone(),
+                request.chapters,
+                tag.clone(),
+                request.options,
+                &request.tonies,
+                job,
+            )?;
+            if let Some(fingerprint) = request.fingerprint {
+                // the Toniefile is fine, a broken index only costs a rebuild next time
+                if let Err(e) = record_build(&content, &tag, fingerprint) {
+                    error!("could not update the build index: {}", e);
+                }
+            }
+            Ok(report)
+        }
+        JobTarget::Append(path) => append_chapters(path, request.chapters, request.options, job),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: JobId,
+    pub description: String,
+    pub state: JobState,
+    pub progress: Progress,
+    pub current_fileno: usize,
+    pub file_count: usize,
+    pub report: Option<DecodeReport>,
+}
+
+struct RunningJob {
+    context: JobContext,
+    handle: thread::JoinHandle<Result<DecodeReport>>,
+}
+
+/// Runs encoding jobs one after another
+#[derive(Default)]
+pub struct JobQueue {
+    jobs: Vec<Job>,
+    pending: VecDeque<(JobId, JobRequest)>,
+    running: Option<RunningJob>,
+    next_id: JobId,
+}
+
+impl JobQueue {
+    pub fn jobs(&self) -> &[Job] {
+        &self.jobs
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.running.is_some() || !self.pending.is_empty()
+    }
+
+    pub fn enqueue(&mut self, request: JobRequest) -> JobId {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.jobs.push(Job {
+            id,
+            description: format!("{}: {} chapters", request.target, request.chapters.len()),
+            state: JobState::Queued,
+            progress: Default::default(),
+            current_fileno: 0,
+            file_count: count_inputs(&request.chapters),
+            report: None,
+        });
+        self.pending.push_back((id, request));
+        info!("queued job {}", id);
+        id
+    }
+
+    fn job_mut(&mut self, id: JobId) -> Option<&mut Job> {
+        self.jobs.iter_mut().find(|j| j.id == id)
+    }
+
+    pub fn cancel(&mut self, id: JobId) {
+        if let Some(running) = &self.running {
+            if running.context.id == id {
+                info!("cancelling running job {}", id);
+                running.context.cancel.store(true, Ordering::Relaxed);
+                return;
+            }
+        }
+        self.pending.retain(|(pending_id, _)| *pending_id != id);
+        if let Some(job) = self.job_mut(id) {
+            if job.state == JobState::Queued {
+                job.state = JobState::Cancelled;
+            }
+        }
+    }
+
+    /// Remove all jobs that are done, failed or cancelled from the list
+    pub fn clear_finished(&mut self) {
+        self.jobs.retain(|j| !j.state.is_finished());
+    }
+
+    pub fn handle_event(&mut self, id: JobId, event: JobEvent) {
+        if let Some(job) = self.job_mut(id) {
+            match event {
+                JobEvent::Progress(p) => job.progress = p,
+                JobEvent::CurrentFileNo(n) => job.current_fileno = n,
+            }
+        }
+    }
+
+    /// Collect the running job if it is done and start the next one
+    ///
+    /// Returns the job that just finished, if any.
+    pub fn poll(&mut self, tx: &Sender<Action>) -> Option<Job> {
+        let mut finished = None;
+        if self
+            .running
+            .as_ref()
+            .is_some_and(|r| r.handle.is_finished())
+        {
+            if let Some(running) = self.running.take() {
+                let result = running
+                    .handle
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("job thread panicked")));
+                let id = running.context.id;
+                if let Some(job) = self.job_mut(id) {
+                    match result {
+                        Ok(report) => {
+                            job.state = JobState::Done;
+                            job.report = Some(report).filter(|r| !r.is_empty());
+                        }
+                        Err(e) if e.is::<JobCancelled>() => job.state = JobState::Cancelled,
+                        Err(e) => {
+                            error!("job {} failed: {}", id, e);
+                            job.state = JobState::Failed(e.to_string());
+                        }
+                    }
+                    finished = Some(job.clone());
+                }
+            }
+        }
+        if self.running.is_none() {
+            if let Some((id, request)) = self.pending.pop_front() {
+                self.start(id, request, tx);
+            }
+        }
+        finished
+    }
+
+    fn start(&mut self, id: JobId, request: JobRequest, tx: &Sender<Action>) {
+        info!("starting job {}", id);
+        let context = JobContext::new(id, tx.clone());
+        let job_context = context.clone();
+        let handle = thread::Builder::new()
+            .name(format!("job_{}", id))
+            .spawn(move || run_request(request, job_context));
+        let Some(job) = self.job_mut(id) else {
+            return;
+        };
+        match handle {
+            Ok(handle) => {
+                job.state = JobState::Running;
+                self.running = Some(RunningJob { context, handle });
+            }
+            Err(e) => job.state = JobState::Failed(e.to_string()),
+        }
+    }
+}
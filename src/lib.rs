@@ -1,18 +1,27 @@
 use anyhow::anyhow;
 use anyhow::Result;
-use crossbeam::channel::Sender;
-use log::{error, info};
+use audiopus::coder::Decoder as OpusDecoder;
+use audiopus::packet as opus_packet;
+use audiopus::{Channels as OpusChannels, SampleRate as OpusSampleRate};
+use crossbeam::channel::{Receiver, Sender};
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
 use std::env;
+use std::fmt::{Display, Formatter};
 use std::fs::{self, DirEntry, File};
-use std::io::{BufReader, Read};
+use std::io::{BufReader, Cursor, Read, Seek, Write};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
 use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
 use symphonia::core::errors::Error as SymphoniaError;
-use symphonia::core::formats::FormatOptions;
-use symphonia::core::io::MediaSourceStream;
+use symphonia::core::formats::{FormatOptions, SeekMode, SeekTo};
+use symphonia::core::io::{MediaSource, MediaSourceStream};
 use symphonia::core::meta::MetadataOptions;
 use symphonia::core::probe::Hint;
+use symphonia::core::units::{Time, TimeBase};
 use toniefile::Toniefile;
 use tonielist::Tonie;
 use ui::Action;
@@ -22,10 +31,41 @@ extern crate lazy_static;
 
 use crate::resampler::Resampler;
 use crate::buffered_source::BufferedSource;
+use crate::capacity::check_free_space;
+use crate::chapters::{total_duration, Chapter};
+use crate::encoder::TafEncoder;
+use crate::decode_report::{DecodeError, DecodeErrorKind, DecodeReport};
+use crate::jobs::{JobContext, JobEvent};
+use crate::trash::{move_to_trash, restore, TrashEntry};
+use crate::options::{
+    apply_gain, normalize_gain, EncodeOptions, SilenceTrimmer, CHANNELS, SAMPLE_RATE,
+};
+use crate::playlist::ChapterInput;
+use crate::progress::ProgressTracker;
+use crate::taf::{Taf, FRAME_SAMPLES};
 
+pub mod backup;
 pub mod buffered_source;
+pub mod capacity;
+pub mod chapters;
+pub mod decode_report;
+pub mod diff;
+pub mod duplicates;
+pub mod encoder;
+pub mod folder;
+pub mod health;
+pub mod index;
+pub mod jobs;
+pub mod manifest;
+pub mod options;
+pub mod playlist;
+pub mod progress;
 pub mod resampler;
+pub mod sync;
+pub mod tags;
+pub mod taf;
 pub mod tonielist;
+pub mod trash;
 pub mod ui;
 
 #[allow(dead_code)]
@@ -64,14 +104,58 @@ impl Teddyfile {
             info,
         }
     }
+
+    /// Series and episode, if it is the Toniefile of a known tonie
+    pub(crate) fn title(&self) -> Option<String> {
+        let data = self.info.as_ref()?.data.first()?;
+        Some(format!(
+            "{} - {}",
+            data.series.clone().unwrap_or_default(),
+            data.episode.clone().unwrap_or_default()
+        ))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether this is a file of the Toniebox itself, see [`is_system_file`]
+    pub fn is_system(&self) -> bool {
+        is_system_file(&self.path)
+    }
+
+    /// What the file is, for files without a known tonie
+    pub fn describe(&self) -> String {
+        match (self.is_system(), self.is_valid) {
+            (true, true) => format!(
+                "system sound, audio ID {}, {} chapters",
+                self.audio_id,
+                self.chapter_pages.len()
+            ),
+            (true, false) => "system data, no audio".to_string(),
+            _ => "unknown".to_string(),
+        }
+    }
 }
 
-fn decode_encode(
-    src: &Path,
-    toniefile: &mut Toniefile<File>,
-    write_tx: Sender<Action>,
+/// Decode and resample an input to 48kHz
+///
+/// The resampled samples are passed to `sink` together with the fraction (0.0 - 1.0) of the input
+/// that is done.
+fn decode_input(
+    input: &ChapterInput,
+    toniefiles: &Toniefiles,
+    report: &Mutex<DecodeReport>,
+    mut sink: impl FnMut(&[i16], f64) -> Result<()>,
 ) -> Result<()> {
-    info!("Encoding input file: {}", src.display());
+    let src = input.path.as_path();
+    info!("Decoding input file: {}", src.display());
+    if let Some(title) = &input.title {
+        info!("Chapter title: {}", title);
+    }
+    if let Some(taf) = toniefiles.get(src) {
+        return decode_toniefile(taf, input, report, sink);
+    }
     let start_time = std::time::Instant::now();
     // if the input file has an extension, use it as a hint for the media format.
     let mut hint = Hint::new();
@@ -84,6 +168,9 @@ fn decode_encode(
     let src = std::fs::File::open(src)?;
 
     let bufsrc = Box::new(BufferedSource::new(src, 1024 * 1024 * 64));
+    // keep track of the read position, in case the stream does not tell us its number of frames
+    let byte_len = bufsrc.byte_len().unwrap_or_default();
+    let byte_pos = bufsrc.position_handle();
     // Create the media source stream.
     let mss = MediaSourceStream::new(bufsrc, Default::default());
 
@@ -107,6 +194,7 @@ fn decode_encode(
 
     // Store the track identifier, it will be used to filter packets.
     let track_id = track.id;
+    let time_base = track.codec_params.time_base;
 
     // create a resampler to convert to 48kHz
     let mut resampler: Option<Resampler<i16>> = None;
@@ -121,16 +209,80 @@ fn decode_encode(
         input_sample_rate, input_channels,
     );
 
-    info!("Track length: {} frames", tracklen);
+    if tracklen > 0 {
+        info!("Track length: {} frames", tracklen);
+    } else {
+        info!("Track length unknown, estimating progress from {} bytes", byte_len);
+    }
+
+    // only encode a part of the file if the chapter comes from a CUE sheet
+    let to_ts = |t: Duration| -> Result<u64> {
+        let time_base = time_base.ok_or(anyhow!(
+            "can not split {}, the time base of the track is unknown",
+            input.path.display()
+        ))?;
+        Ok(time_base.calc_timestamp(Time::from(t.as_secs_f64())))
+    };
+    let mut start_ts = 0;
+    if let Some(start) = input.start.filter(|s| !s.is_zero()) {
+        info!("Seeking to {:?}", start);
+        let seeked = format.seek(
+            SeekMode::Accurate,
+            SeekTo::Time {
+                time: Time::from(start.as_secs_f64()),
+                track_id: Some(track_id),
+            },
+        )?;
+        decoder.reset();
+        start_ts = seeked.required_ts;
+    }
+    let end_ts = input.end.map(to_ts).transpose()?;
+    let len_ts = match end_ts {
+        Some(end_ts) => end_ts.saturating_sub(start_ts),
+        None => tracklen.saturating_sub(start_ts),
+    };
 
-    let mut progress = 0;
-    while let Ok(packet) = format.next_packet() {
-        let newprogress = packet.ts * 100 / tracklen;
-        if packet.ts * 100 / tracklen != progress {
-            progress = newprogress;
-            info!("Progress: {}%", progress);
-            write_tx.send(Action::Processing(progress))?;
+    let mut demux_errors = 0;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break
+            }
+            Err(SymphoniaError::ResetRequired) => break,
+            Err(err) => {
+                let kind = match err {
+                    SymphoniaError::IoError(_) => DecodeErrorKind::Io,
+                    _ => DecodeErrorKind::Decode,
+                };
+                lock_report(report).record(DecodeError {
+                    file: input.path.clone(),
+                    timestamp: None,
+                    kind,
+                    message: format!("could not read the next packet: {}", err),
+                })?;
+                // the reader skips to the next page/frame after malformed data, anything else
+                // (or a reader that keeps failing) ends the input
+                demux_errors += 1;
+                let malformed = matches!(err, SymphoniaError::DecodeError(_));
+                if malformed && demux_errors < MAX_DEMUX_ERRORS {
+                    continue;
+                }
+                warn!("Stopped reading {:?} after a read error", input.path);
+                break;
+            }
+        };
+        demux_errors = 0;
+        if end_ts.is_some_and(|end_ts| packet.ts >= end_ts) {
+            break;
         }
+        let fraction = if len_ts > 0 {
+            packet.ts.saturating_sub(start_ts) as f64 / len_ts as f64
+        } else if byte_len > 0 {
+            byte_pos.load(Ordering::Relaxed) as f64 / byte_len as f64
+        } else {
+            0.0
+        };
 
         // Consume any new metadata that has been read since the last packet.
         while !format.metadata().is_latest() {
@@ -138,7 +290,8 @@ fn decode_encode(
         }
 
         // If the packet does not belong to the selected track, skip over it.
-        if packet.track_id() != track_id {
+        // Accurate seeking can also return some packets before the requested position.
+        if packet.track_id() != track_id || packet.ts < start_ts {
             continue;
         }
 
@@ -155,18 +308,28 @@ fn decode_encode(
                 }
                 if let Some(res) = resampler.as_mut() {
                     if let Some(resampled) = res.resample(decoded) {
-                        toniefile.encode(resampled)?;
+                        sink(resampled, fraction)?;
                     }
                 }
             }
-            Err(SymphoniaError::IoError(_)) => {
+            Err(SymphoniaError::IoError(e)) => {
                 // The packet failed to decode due to an IO error, skip the packet.
-                info!("IO error");
+                lock_report(report).record(DecodeError {
+                    file: input.path.clone(),
+                    timestamp: packet_time(time_base, packet.ts),
+                    kind: DecodeErrorKind::Io,
+                    message: e.to_string(),
+                })?;
                 continue;
             }
-            Err(SymphoniaError::DecodeError(_)) => {
+            Err(SymphoniaError::DecodeError(e)) => {
                 // The packet failed to decode due to invalid data, skip the packet.
-                info!("Decode error");
+                lock_report(report).record(DecodeError {
+                    file: input.path.clone(),
+                    timestamp: packet_time(time_base, packet.ts),
+                    kind: DecodeErrorKind::Decode,
+                    message: e.to_string(),
+                })?;
                 continue;
             }
             Err(err) => {
@@ -175,12 +338,93 @@ fn decode_encode(
             }
         }
     }
-    info!("Progress: 100%");
     info!("File done");
     info!("Time to decode: {} seconds", std::time::Instant::now().duration_since(start_time).as_secs());
     Ok(())
 }
 
+/// Number of packets decoded in front of the start of an input from a Toniefile, so the Opus
+/// decoder has settled when the input starts
+const DECODER_WARMUP_PACKETS: u64 = 4;
+/// Consecutive unreadable packets after which an input is given up
+const MAX_DEMUX_ERRORS: usize = 16;
+
+/// Decode the audio of a Toniefile between the start and the end of `input`
+///
+/// Works like [`decode_input`], but the Opus packets are decoded directly, Toniefiles already
+/// are at 48kHz.
+fn decode_toniefile(
+    taf: &Taf,
+    input: &ChapterInput,
+    report: &Mutex<DecodeReport>,
+    mut sink: impl FnMut(&[i16], f64) -> Result<()>,
+) -> Result<()> {
+    let to_samples = |t: Duration| (t.as_secs_f64() * SAMPLE_RATE as f64).round() as u64;
+    // positions are counted like the granule positions, including the pre-skip
+    let start = input
+        .start
+        .map(to_samples)
+        .unwrap_or_default()
+        .max(taf.pre_skip());
+    let end = input.end.map(to_samples).unwrap_or(u64::MAX).min(taf.samples());
+    let len = end.saturating_sub(start);
+    let warmup_start = start.saturating_sub(DECODER_WARMUP_PACKETS * FRAME_SAMPLES as u64);
+
+    let mut decoder = OpusDecoder::new(OpusSampleRate::Hz48000, OpusChannels::Stereo)?;
+    // an Opus packet has at most 120 ms
+    let mut output = vec![0; 2 * FRAME_SAMPLES * CHANNELS];
+    // start at the chapter the input is in, chapters are decoded separately
+    let (mut position, packets) = taf.packets_from(warmup_start);
+    for packet in packets {
+        if position >= end {
+            break;
+        }
+        let packet_samples = opus_packet::nb_samples(packet, OpusSampleRate::Hz48000)
+            .unwrap_or(FRAME_SAMPLES) as u64;
+        let packet_start = position;
+        position += packet_samples;
+        if position <= warmup_start {
+            continue;
+        }
+        let decoded = match decoder.decode(Some(packet), &mut output[..], false) {
+            Ok(decoded) => decoded as u64,
+            Err(e) => {
+                // skip the packet like a broken packet of any other input
+                lock_report(report).record(DecodeError {
+                    file: input.path.clone(),
+                    timestamp: Some(Duration::from_secs_f64(
+                        packet_start as f64 / SAMPLE_RATE as f64,
+                    )),
+                    kind: DecodeErrorKind::Decode,
+                    message: e.to_string(),
+                })?;
+                continue;
+            }
+        };
+        // only pass on the part of the packet between start and end
+        let from = start.clamp(packet_start, packet_start + decoded) - packet_start;
+        let to = end.clamp(packet_start, packet_start + decoded) - packet_start;
+        if from < to {
+            let fraction = (packet_start + to - start) as f64 / len.max(1) as f64;
+            sink(
+                &output[from as usize * CHANNELS..to as usize * CHANNELS],
+                fraction,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn lock_report(report: &Mutex<DecodeReport>) -> MutexGuard<'_, DecodeReport> {
+    // a panicking decoder thread can not leave the report in an inconsistent state
+    report.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn packet_time(time_base: Option<TimeBase>, ts: u64) -> Option<Duration> {
+    let time = time_base?.calc_time(ts);
+    Some(Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac))
+}
+
 fn write_table_entry(
     entry: DirEntry,
     files: &mut Vec<Teddyfile>,
@@ -189,7 +433,7 @@ fn write_table_entry(
     let mut f = File::open(entry.path())?;
     match Toniefile::parse_header(&mut f) {
         Ok(header) => {
-            let info = tonielist::find_tonie_with_audio_id(&tonielist, header.audio_id);
+            let info = tonielist::find_tonie_with_audio_id(tonielist, header.audio_id);
             files.push(Teddyfile::new(
                 entry.path(),
                 true,
@@ -201,6 +445,20 @@ fn write_table_entry(
                 info,
             ));
         }
+        // not all files of the Toniebox itself are Toniefiles
+        Err(e) if is_system_file(&entry.path()) => {
+            debug!("{} is no Toniefile: {}", entry.path().display(), e);
+            files.push(Teddyfile::new(
+                entry.path(),
+                false,
+                vec![],
+                0,
+                0,
+                vec![],
+                get_tag_id(&entry.path()).unwrap_or("invalid".into()),
+                None,
+            ));
+        }
         Err(e) => {
             error!("error reading header from file {}", entry.path().display());
             error!("error: {}", e);
@@ -237,7 +495,7 @@ fn rotate_bytewise(input: &str) -> String {
     )
 }
 
-fn get_tag_id(path: &Path) -> Option<String> {
+pub(crate) fn get_tag_id(path: &Path) -> Option<String> {
     let mut ancestors = path.ancestors();
     let firsthalf = ancestors.next()?.file_name()?.to_str()?;
     let secondhalf = ancestors.next()?.file_name()?.to_str()?;
@@ -264,41 +522,192 @@ fn read_audio_from_file(file: &Teddyfile) -> Result<Vec<u8>> {
     Ok(buf[header_len + 4..].to_vec())
 }
 
+/// Write the audio of a Toniefile to an Ogg file
+///
+/// System files of the Toniebox that are no Toniefiles are copied as they are.
 pub fn extract_to_ogg(file: &Teddyfile, dest: &Path, write_tx: Sender<Action>) -> Result<()> {
+    write_tx.send(Action::CurrentFile(format!(
+        "Extracting file: {}",
+        file.path.display()
+    )))?;
+    if !file.is_valid && file.is_system() {
+        fs::copy(&file.path, dest)?;
+        write_tx.send(Action::CurrentFile("".to_string()))?;
+        return Ok(());
+    }
     if !file.is_valid {
         error!("file {} has an invalid header", file.path.display());
     }
-    write_tx.send(Action::CurrentFile(file.path.to_string_lossy().to_string()))?;
     let audio = read_audio_from_file(file)?;
     fs::write(dest.join(dest).with_extension("ogg"), audio)?;
     write_tx.send(Action::CurrentFile("".to_string()))?;
     Ok(())
 }
 
-pub fn change_tag_id(picked_path: &Path, file: &Teddyfile, tag: &str) -> Result<()> {
-    let (filename, dirname) = tag.split_at(8);
-    let (filename, dirname) = (
-        filename.to_string().to_ascii_uppercase(),
-        dirname.to_string().to_ascii_uppercase(),
-    );
-    let dest = picked_path.join(rotate_bytewise(&dirname));
-    fs::create_dir(&dest)?;
-    fs::copy(&file.path, dest.join(rotate_bytewise(&filename)))?;
-    fs::remove_file(&file.path)?;
-    if let Some(parent) = file.path.parent() {
-        fs::remove_dir(parent)?;
+/// Folders of the Toniebox itself, like the one with the system sounds, start with this
+pub const SYSTEM_DIR_PREFIX: &str = "000000";
+
+/// Whether a file is in one of the folders of the Toniebox itself
+pub fn is_system_file(path: &Path) -> bool {
+    path.parent()
+        .and_then(Path::file_name)
+        .is_some_and(|dir| dir.to_string_lossy().starts_with(SYSTEM_DIR_PREFIX))
+}
+
+/// Refuse to change the files of the Toniebox itself, they can only be played and extracted
+pub(crate) fn check_not_system(path: &Path) -> Result<()> {
+    if is_system_file(path) {
+        return Err(anyhow!(
+            "{} is a system file of the Toniebox, it can only be played and extracted",
+            path.display()
+        ));
     }
     Ok(())
 }
 
-pub fn delete_file(file: &Teddyfile) -> Result<()> {
-    fs::remove_file(&file.path)?;
-    if let Some(parent) = file.path.parent() {
-        fs::remove_dir(parent)?;
+/// Returned by [`change_tag_id`] if there already is a Toniefile for the new tag ID
+#[derive(Debug)]
+pub struct TagInUse(pub String);
+
+impl Display for TagInUse {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "there already is a Toniefile for tag {}", self.0)
+    }
+}
+
+impl std::error::Error for TagInUse {}
+
+/// Move a Toniefile to the place of another tag ID
+///
+/// The file is renamed, so the audio is not copied. An existing Toniefile for `tag` is only
+/// replaced with `overwrite`, otherwise [`TagInUse`] is returned. The replaced Toniefile goes to
+/// the trash and is returned. If anything goes wrong, all files end up where they were.
+pub fn change_tag_id(
+    content: &Path,
+    path: &Path,
+    tag: &str,
+    overwrite: bool,
+) -> Result<Option<TrashEntry>> {
+    let tag = tag.to_ascii_uppercase();
+    let dest = tag_path(content, &tag)?;
+    check_not_system(path)?;
+    check_not_system(&dest)?;
+    if dest == path {
+        return Ok(None);
+    }
+    if dest.exists() && !overwrite {
+        return Err(TagInUse(tag).into());
+    }
+    let dir = dest
+        .parent()
+        .ok_or(anyhow!("{} has no parent folder", dest.display()))?;
+    let created_dir = !dir.exists();
+    if created_dir {
+        fs::create_dir(dir)?;
+    }
+    let replaced = if dest.exists() {
+        Some(move_to_trash(content, &dest)?)
+    } else {
+        None
+    };
+    if let Err(e) = move_file(path, &dest) {
+        if let Some(replaced) = &replaced {
+            let _ = restore(content, replaced, false);
+        }
+        if created_dir {
+            let _ = fs::remove_dir(dir);
+        }
+        return Err(e);
+    }
+    info!("moved {} to {}", path.display(), dest.display());
+    // the Toniefile is already moved, a leftover empty folder is no reason to report a failure
+    if let Some(parent) = path.parent() {
+        if let Err(e) = remove_dir_if_empty(parent) {
+            error!("can not remove the folder {}: {}", parent.display(), e);
+        }
+    }
+    Ok(replaced)
+}
+
+/// Exchange the places of two Toniefiles, so each one plays for the tag of the other
+///
+/// One file is moved out of the way while the other takes its place. If a step fails, the
+/// steps before it are undone, so either both files are swapped or none.
+pub fn swap_toniefiles(first: &Path, second: &Path) -> Result<()> {
+    if first == second {
+        return Err(anyhow!("can not swap {} with itself", first.display()));
+    }
+    for path in [first, second] {
+        check_not_system(path)?;
+        if !path.is_file() {
+            return Err(anyhow!("there is no Toniefile at {}", path.display()));
+        }
+    }
+    let parked = first.with_extension(TEMP_EXTENSION);
+    move_file(first, &parked)?;
+    if let Err(e) = move_file(second, first) {
+        let _ = move_file(&parked, first);
+        return Err(e);
+    }
+    if let Err(e) = move_file(&parked, second) {
+        let _ = move_file(first, second);
+        let _ = move_file(&parked, first);
+        return Err(e);
+    }
+    info!("swapped {} and {}", first.display(), second.display());
+    Ok(())
+}
+
+/// Rename a file, or copy it and remove the original if renaming does not work
+pub(crate) fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if let Err(e) = fs::rename(from, to) {
+        debug!("renaming {} failed: {}, copying it", from.display(), e);
+        if let Err(e) = fs::copy(from, to) {
+            let _ = fs::remove_file(to);
+            return Err(e.into());
+        }
+        fs::remove_file(from)?;
     }
     Ok(())
 }
 
+/// Remove a folder once it holds no more files
+pub(crate) fn remove_dir_if_empty(dir: &Path) -> Result<()> {
+    if dir.read_dir()?.next().is_none() {
+        fs::remove_dir(dir)?;
+    }
+    Ok(())
+}
+
+/// Put the Toniefile at `new` in the place of `path`, the Toniefile there goes to the trash
+pub(crate) fn replace_toniefile(content: &Path, new: &Path, path: &Path) -> Result<()> {
+    check_not_system(path)?;
+    let replaced = if path.exists() {
+        Some(move_to_trash(content, path)?)
+    } else {
+        None
+    };
+    if let Err(e) = fs::rename(new, path) {
+        if let Some(replaced) = &replaced {
+            let _ = restore(content, replaced, false);
+        }
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Move a Toniefile to the trash, see [`trash`]
+pub fn delete_file(content: &Path, file: &Teddyfile) -> Result<TrashEntry> {
+    let entry = move_to_trash(content, &file.path)?;
+    // the trash entry is needed to undo the deletion, so it is returned even if the folder stays
+    if let Some(parent) = file.path.parent() {
+        if let Err(e) = remove_dir_if_empty(parent) {
+            error!("can not remove the folder {}: {}", parent.display(), e);
+        }
+    }
+    Ok(entry)
+}
+
 pub fn extract_all(files: &[Teddyfile], path: &Path, write_tx: Sender<Action>) -> Result<()> {
     for file in files {
         extract_to_ogg(file, &path.join(&file.tag), write_tx.clone()).unwrap_or_else(|e| {
@@ -309,6 +718,9 @@ pub fn extract_all(files: &[Teddyfile], path: &Path, write_tx: Sender<Action>) -
 }
 
 pub fn play_file(file: &Teddyfile, write_tx: Sender<Action>) -> Result<()> {
+    if !file.is_valid && file.is_system() {
+        return Err(anyhow!("{} has no audio", file.path.display()));
+    }
     let dir = env::temp_dir();
     let path = dir
         .join(file.path.file_name().unwrap_or_default())
@@ -337,10 +749,13 @@ pub fn populate_table(
     for entry in path.read_dir()?.flatten() {
         if entry.path().is_dir() {
             if let Some(filename) = entry.path().file_name() {
-                if !filename.to_string_lossy().starts_with("000000") {
+                if !filename.to_string_lossy().starts_with(SYSTEM_DIR_PREFIX) {
                     for entry in entry.path().read_dir()?.flatten() {
-                        if entry.path().is_file() {
-                            write_table_entry(entry, files, &tonielist)?;
+                        // skip Toniefiles that are being encoded right now
+                        if entry.path().is_file()
+                            && entry.path().extension() != Some(TEMP_EXTENSION.as_ref())
+                        {
+                            write_table_entry(entry, files, tonielist)?;
                         }
                     }
                 }
@@ -350,36 +765,339 @@ pub fn populate_table(
     Ok(())
 }
 
-pub fn add_audio_file(
-    dest: PathBuf,
-    infiles: Vec<PathBuf>,
-    tag: String,
-    write_tx: Sender<Action>,
+/// Add the files in the folders of the Toniebox itself, like its system sounds
+///
+/// Files that are no Toniefiles are listed as invalid. Nothing in these folders can be deleted or
+/// changed, see [`check_not_system`].
+pub fn populate_system_files(
+    path: &Path,
+    files: &mut Vec<Teddyfile>,
+    tonielist: &Arc<Vec<Tonie>>,
 ) -> Result<()> {
+    for entry in path.read_dir()?.flatten() {
+        if entry.path().is_dir()
+            && entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(SYSTEM_DIR_PREFIX)
+        {
+            for entry in entry.path().read_dir()?.flatten() {
+                if entry.path().is_file() {
+                    write_table_entry(entry, files, tonielist)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Extension of the file a Toniefile is encoded into before it replaces the real one
+pub const TEMP_EXTENSION: &str = "rbtmp";
+
+/// A file size in kB, MB or GB, like file managers show them
+pub fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..=999_999 => format!("{} kB", bytes / 1000),
+        1_000_000..=999_999_999 => format!("{:.1} MB", bytes as f64 / 1e6),
+        _ => format!("{:.1} GB", bytes as f64 / 1e9),
+    }
+}
+
+/// Path of the Toniefile for a tag ID in a CONTENT folder, fails for anything that is no tag ID
+pub fn tag_path(content: &Path, tag: &str) -> Result<PathBuf> {
+    check_tag_id_validity(tag)?;
     let (filename, dirname) = tag.split_at(8);
     let (filename, dirname) = (
         filename.to_string().to_ascii_uppercase(),
         dirname.to_string().to_ascii_uppercase(),
     );
-    let dest = dest.join(rotate_bytewise(&dirname));
-    let _ = fs::create_dir(&dest);
-    let destfile = File::create(dest.join(rotate_bytewise(&filename)))?;
-
-    let mut toniefile = Toniefile::new_simple(destfile)?;
-
-    let mut infiles_iter = infiles.iter();
-    let first_path = infiles_iter.next().ok_or(anyhow!("no input files"))?;
-    let mut i = 1;
-    write_tx.send(Action::CurrentFileNo(i))?;
-    decode_encode(first_path, &mut toniefile, write_tx.clone())?;
-    for file in infiles_iter {
-        i += 1;
-        write_tx.send(Action::CurrentFileNo(i))?;
-        toniefile.new_chapter()?;
-        decode_encode(file, &mut toniefile, write_tx.clone())?;
-    }
-    info!("all files encoded, finalizing...");
-    toniefile.finalize()?;
-    write_tx.send(Action::PopulateTable)?;
+    Ok(content
+        .join(rotate_bytewise(&dirname))
+        .join(rotate_bytewise(&filename)))
+}
+
+pub fn add_audio_file(
+    dest: PathBuf,
+    chapters: Vec<Chapter>,
+    tag: String,
+    options: EncodeOptions,
+    tonies: &Arc<Vec<Tonie>>,
+    job: JobContext,
+) -> Result<DecodeReport> {
+    // a full card would only make the job fail halfway
+    match total_duration(&chapters) {
+        Some(duration) => {
+            check_free_space(&dest, options.opus.estimated_size(duration, chapters.len()))?
+        }
+        None => warn!("the length of some inputs is unknown, the free space is not checked"),
+    }
+    let destpath = tag_path(&dest, &tag)?;
+    check_not_system(&destpath)?;
+    if let Some(parent) = destpath.parent() {
+        let _ = fs::create_dir(parent);
+    }
+    // encode next to the destination, so an existing Toniefile is only replaced when the new one
+    // is complete
+    // before the file is created, the audio ID can come from the Toniefile that is replaced
+    let audio_id = options.audio_id.resolve(tonies)?;
+    let temppath = destpath.with_extension(TEMP_EXTENSION);
+    let destfile = File::create(&temppath)?;
+
+    let mut report = DecodeReport::new(options.policy);
+    let result = TafEncoder::new(destfile, audio_id, options.opus)
+        .and_then(|mut toniefile| {
+            encode_files(&mut toniefile, &chapters, &options, &mut report, &job)?;
+            info!("all files encoded, finalizing...");
+            toniefile.finalize()
+        })
+        .and_then(|_| replace_toniefile(&dest, &temppath, &destpath));
+    if let Err(e) = result {
+        // do not leave a half written file behind
+        let _ = fs::remove_file(&temppath);
+        return Err(e);
+    }
+    Ok(report)
+}
+
+/// Encode `chapters` and add them at the end of an existing Toniefile
+///
+/// Only the new chapters are encoded, the audio already in the Toniefile is kept as it is.
+pub fn append_chapters(
+    path: PathBuf,
+    chapters: Vec<Chapter>,
+    options: EncodeOptions,
+    job: JobContext,
+) -> Result<DecodeReport> {
+    check_not_system(&path)?;
+    let taf = Taf::read(&path)?;
+    match (total_duration(&chapters), path.parent()) {
+        (Some(duration), Some(dir)) => {
+            // the whole Toniefile is written again next to the old one
+            let size = fs::metadata(&path)?.len();
+            check_free_space(
+                dir,
+                size + options.opus.estimated_size(duration, chapters.len()),
+            )?;
+        }
+        _ => warn!("the length of some inputs is unknown, the free space is not checked"),
+    }
+    // encode the new chapters as Toniefile of their own with the same audio ID and take its blocks
+    let mut toniefile = TafEncoder::new(Cursor::new(vec![]), taf.header.audio_id, options.opus)?;
+    let mut report = DecodeReport::new(options.policy);
+    encode_files(&mut toniefile, &chapters, &options, &mut report, &job)?;
+    let appended = Taf::from_bytes(toniefile.finalize()?.get_ref())?;
+
+    let mut all_chapters = taf.chapters();
+    info!(
+        "appending {} chapters to the {} of {}",
+        chapters.len(),
+        all_chapters.len(),
+        path.display()
+    );
+    all_chapters.extend(appended.chapters());
+    taf::write_atomic(&path, &taf.rebuild(all_chapters)?)?;
+    Ok(report)
+}
+
+fn encode_files<W: Write + Seek>(
+    toniefile: &mut TafEncoder<W>,
+    chapters: &[Chapter],
+    options: &EncodeOptions,
+    report: &mut DecodeReport,
+    job: &JobContext,
+) -> Result<()> {
+    let infiles: Vec<&ChapterInput> = chapters.iter().flat_map(|c| &c.inputs).collect();
+    if infiles.is_empty() {
+        return Err(anyhow!("no input files"));
+    }
+
+    // weigh the input files by size to get the progress over the whole job,
+    // chapters from the same file (CUE sheets) share its size
+    let sizes = infiles
+        .iter()
+        .map(|f| {
+            let size = fs::metadata(&f.path).map(|m| m.len()).unwrap_or_default();
+            size / infiles.iter().filter(|o| o.path == f.path).count() as u64
+        })
+        .collect();
+    let mut tracker = ProgressTracker::new(sizes);
+
+    // a Toniefile is parsed once, even if all of its chapters are inputs
+    let mut toniefiles = Toniefiles::new();
+    for input in &infiles {
+        if !toniefiles.contains_key(&input.path) && taf::is_toniefile(&input.path) {
+            toniefiles.insert(input.path.clone(), Taf::read(&input.path)?);
+        }
+    }
+
+    // The inputs are decoded by a pool of workers, every input gets its own bounded queue. The
+    // encoder empties the queues in chapter order, so the order is deterministic and a worker that
+    // is ahead has to wait until its queue is drained.
+    let shared_report = Mutex::new(std::mem::take(report));
+    let (senders, receivers): (Vec<_>, Vec<_>) = infiles
+        .iter()
+        .map(|_| crossbeam::channel::bounded::<DecodedSamples>(DECODE_QUEUE_LEN))
+        .unzip();
+    let senders: Vec<Mutex<Option<Sender<DecodedSamples>>>> =
+        senders.into_iter().map(|s| Mutex::new(Some(s))).collect();
+    let next_input = AtomicUsize::new(0);
+    let abort = AtomicBool::new(false);
+    let workers = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(infiles.len());
+    info!("decoding {} files with {} threads", infiles.len(), workers);
+
+    let result = thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                decode_worker(
+                    &infiles,
+                    &toniefiles,
+                    options,
+                    &senders,
+                    &next_input,
+                    &abort,
+                    &shared_report,
+                )
+            });
+        }
+        let result = encode_queues(
+            toniefile,
+            chapters,
+            options,
+            &receivers,
+            &mut tracker,
+            job,
+        );
+        // stop the workers, they notice it at their next send
+        abort.store(true, Ordering::Relaxed);
+        drop(receivers);
+        result
+    });
+    *report = shared_report
+        .into_inner()
+        .unwrap_or_else(|e| e.into_inner());
+    result
+}
+
+/// Number of resampled buffers a decoder worker may queue up per input
+const DECODE_QUEUE_LEN: usize = 64;
+
+/// Resampled samples and the fraction of the input that is done, or the error that stopped the
+/// decoder
+type DecodedSamples = Result<(Vec<i16>, f64)>;
+
+/// The Toniefiles among the inputs of an encoding, parsed
+type Toniefiles = HashMap<PathBuf, Taf>;
+
+fn decode_worker(
+    infiles: &[&ChapterInput],
+    toniefiles: &Toniefiles,
+    options: &EncodeOptions,
+    senders: &[Mutex<Option<Sender<DecodedSamples>>>],
+    next_input: &AtomicUsize,
+    abort: &AtomicBool,
+    report: &Mutex<DecodeReport>,
+) {
+    while !abort.load(Ordering::Relaxed) {
+        let i = next_input.fetch_add(1, Ordering::Relaxed);
+        let Some(input) = infiles.get(i) else {
+            break;
+        };
+        // taking the sender closes the queue when this input is done
+        let Some(tx) = senders[i].lock().ok().and_then(|mut s| s.take()) else {
+            continue;
+        };
+        let gain = if options.normalize {
+            input_gain(input, toniefiles)
+        } else {
+            Ok(1.0)
+        };
+        let mut trimmer = SilenceTrimmer::default();
+        let result = gain.and_then(|gain| {
+            decode_input(input, toniefiles, report, |samples, fraction| {
+                let samples = if options.trim_silence {
+                    trimmer.trim(samples)
+                } else {
+                    samples.to_vec()
+                };
+                let samples = if gain == 1.0 {
+                    samples
+                } else {
+                    apply_gain(&samples, gain)
+                };
+                tx.send(Ok((samples, fraction)))
+                    .map_err(|_| anyhow!("encoding was aborted"))
+            })
+        });
+        if let Err(e) = result {
+            let _ = tx.send(Err(e));
+        }
+    }
+}
+
+/// Decode an input once to find the gain that normalizes it
+fn input_gain(input: &ChapterInput, toniefiles: &Toniefiles) -> Result<f32> {
+    info!("Scanning peak level of {}", input.path.display());
+    // broken packets are recorded when the input is decoded for encoding
+    let scan_report = Mutex::new(DecodeReport::default());
+    let mut peak = 0;
+    decode_input(input, toniefiles, &scan_report, |samples, _| {
+        peak = samples.iter().fold(peak, |p, s| p.max(s.unsigned_abs()));
+        Ok(())
+    })?;
+    let gain = normalize_gain(peak);
+    info!("Peak level {}, applying gain {:.2}", peak, gain);
+    Ok(gain)
+}
+
+fn encode_queues<W: Write + Seek>(
+    toniefile: &mut TafEncoder<W>,
+    chapters: &[Chapter],
+    options: &EncodeOptions,
+    receivers: &[Receiver<DecodedSamples>],
+    tracker: &mut ProgressTracker,
+    job: &JobContext,
+) -> Result<()> {
+    let mut queues = receivers.iter();
+    let mut i = 0;
+    for (chapter_no, chapter) in chapters.iter().enumerate() {
+        // the first chapter is started by the Toniefile itself
+        if chapter_no > 0 {
+            toniefile.new_chapter()?;
+        }
+        for input in &chapter.inputs {
+            if i > 0 {
+                tracker.next_file();
+            }
+            i += 1;
+            info!("Encoding input file: {}", input.path.display());
+            job.send(JobEvent::CurrentFileNo(i))?;
+            let queue = queues.next().ok_or(anyhow!("missing decoder queue"))?;
+            let mut progress = tracker.progress(0.0);
+            for decoded in queue {
+                job.check_cancelled()?;
+                let (samples, fraction) = decoded?;
+                toniefile.encode(&samples)?;
+                let newprogress = tracker.progress(fraction);
+                if newprogress.file_percent != progress.file_percent
+                    || newprogress.total_percent != progress.total_percent
+                {
+                    info!(
+                        "Progress: {}% (total {}%)",
+                        newprogress.file_percent, newprogress.total_percent
+                    );
+                    job.send(JobEvent::Progress(newprogress.clone()))?;
+                }
+                progress = newprogress;
+            }
+            info!("Progress: 100%");
+            job.send(JobEvent::Progress(tracker.progress(1.0)))?;
+        }
+        if chapter_no + 1 < chapters.len() && !options.gap.is_zero() {
+            toniefile.encode(&options.gap_samples())?;
+        }
+    }
     Ok(())
 }
@@ -1,14 +1,912 @@
-use clap::Parser;
+use anyhow::{anyhow, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use crossbeam::channel::Sender;
 use eframe::{egui::ViewportBuilder, run_native};
-use rusty_bench::ui::RustyBench;
+use rusty_bench::backup::{backup, restore_backup};
+use rusty_bench::capacity::card_usage;
+use rusty_bench::chapters::{
+    format_duration, merge_with_next, plan_chapters, total_duration, Chapter,
+};
+use rusty_bench::decode_report::DecodeErrorPolicy;
+use rusty_bench::diff::diff_report;
+use rusty_bench::duplicates::find_duplicates;
+use rusty_bench::health::{check_health, repair_all, Problem};
+use rusty_bench::jobs::{run_request, JobContext, JobEvent};
+use rusty_bench::manifest::{plan_build, unlisted_tags, BuildAction, Manifest};
+use rusty_bench::options::{AudioId, EncodeOptions, OpusSettings};
+use rusty_bench::playlist::expand_inputs;
+use rusty_bench::sync::{apply_all, compare, Direction};
+use rusty_bench::taf::{
+    merge_files, set_audio_id, split_chapters, toniefile_inputs, ChapterLayout, SourcesLeft,
+};
+use rusty_bench::tonielist::{get_tonie_list_online, Tonie};
+use rusty_bench::trash::{empty_trash, list_trash, restore, TrashEntry};
+use rusty_bench::ui::{Action, RustyBench};
+use rusty_bench::{
+    add_audio_file, append_chapters, change_tag_id, check_tag_id_validity, extract_to_ogg,
+    format_size, populate_system_files, swap_toniefiles, tag_path, TagInUse, Teddyfile,
+};
+use std::fs::{self, File};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use toniefile::Toniefile;
 
 #[derive(Debug, Parser)]
 struct Cli {
     #[command(flatten)]
     verbose: clap_verbosity_flag::Verbosity,
+    /// Run a single operation without starting the GUI
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-fn main() {
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Encode audio files into a new Toniefile, one chapter per file
+    Add {
+        /// CONTENT folder to write the Toniefile to
+        #[arg(short, long)]
+        content: PathBuf,
+        /// Tag ID (16 hex digits) the Toniefile is coupled with
+        #[arg(short, long)]
+        tag: String,
+        #[command(flatten)]
+        encode: EncodeArgs,
+        #[command(flatten)]
+        audio_id: AudioIdArgs,
+        /// Audio files to encode, playlists (m3u, m3u8, pls) and CUE sheets are expanded into
+        /// their entries, folders into the audio files they contain
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Encode audio files and add them as new chapters at the end of an existing Toniefile, the
+    /// audio already in the Toniefile is kept as it is
+    Append {
+        /// CONTENT folder with the Toniefile
+        #[arg(short, long)]
+        content: PathBuf,
+        /// Tag ID (16 hex digits) of the Toniefile
+        #[arg(short, long)]
+        tag: String,
+        #[command(flatten)]
+        encode: EncodeArgs,
+        /// Audio files to encode, like for `add`
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// List the chapters of a Toniefile, or remove, reorder and merge them without encoding the
+    /// audio again
+    Chapters {
+        /// CONTENT folder with the Toniefile
+        #[arg(short, long)]
+        content: PathBuf,
+        /// Tag ID (16 hex digits) of the Toniefile
+        #[arg(short, long)]
+        tag: String,
+        /// New chapters as comma separated chapter numbers, chapters joined with + are merged and
+        /// chapters that are left out are removed, e.g. `3,1+2` or `1,2,4`
+        #[arg(short, long)]
+        layout: Option<String>,
+    },
+    /// Decode a Toniefile and encode it again, e.g. to clean up Toniefiles made by other tools
+    Reencode {
+        /// CONTENT folder with the Toniefile
+        #[arg(short, long)]
+        content: PathBuf,
+        /// Tag ID (16 hex digits) of the Toniefile
+        #[arg(short, long)]
+        tag: String,
+        #[command(flatten)]
+        encode: EncodeArgs,
+        /// The re-encoded Toniefile keeps its audio ID unless another one is given
+        #[command(flatten)]
+        audio_id: AudioIdArgs,
+        /// New chapters like for the chapters command
+        #[arg(short, long)]
+        layout: Option<String>,
+    },
+    /// Show the audio ID of a Toniefile, or give it another one
+    AudioId {
+        /// CONTENT folder with the Toniefile
+        #[arg(short, long)]
+        content: PathBuf,
+        /// Tag ID (16 hex digits) of the Toniefile
+        #[arg(short, long)]
+        tag: String,
+        #[command(flatten)]
+        audio_id: AudioIdArgs,
+    },
+    /// Move a Toniefile to another tag ID
+    Retag {
+        /// CONTENT folder with the Toniefile
+        #[arg(short, long)]
+        content: PathBuf,
+        /// Tag ID (16 hex digits) of the Toniefile
+        #[arg(short, long)]
+        tag: String,
+        /// Tag ID (16 hex digits) the Toniefile is moved to
+        #[arg(long)]
+        new_tag: String,
+        /// Replace the Toniefile of the new tag if there is one
+        #[arg(long)]
+        force: bool,
+    },
+    /// Exchange the Toniefiles of two tags
+    Swap {
+        /// CONTENT folder with the Toniefiles
+        #[arg(short, long)]
+        content: PathBuf,
+        /// Tag IDs (16 hex digits) of the two Toniefiles
+        #[arg(num_args = 2, required = true, value_name = "TAG")]
+        tags: Vec<String>,
+    },
+    /// Write a range of chapters of a Toniefile as a new Toniefile for another tag
+    Split {
+        /// CONTENT folder with the Toniefile
+        #[arg(short, long)]
+        content: PathBuf,
+        /// Tag ID (16 hex digits) of the Toniefile
+        #[arg(short, long)]
+        tag: String,
+        /// Chapter numbers as `first-last`, or a single chapter number
+        #[arg(long)]
+        chapters: String,
+        /// Tag ID (16 hex digits) for the new Toniefile
+        #[arg(long)]
+        new_tag: String,
+        /// Take the chapters out of the original Toniefile
+        #[arg(long)]
+        remove: bool,
+    },
+    /// Concatenate the chapters of several Toniefiles into one
+    Merge {
+        /// CONTENT folder with the Toniefiles
+        #[arg(short, long)]
+        content: PathBuf,
+        /// Tag ID (16 hex digits) of the merged Toniefile, can be one of the merged tags
+        #[arg(short, long)]
+        tag: String,
+        /// Tag IDs of the Toniefiles to merge, in this order
+        #[arg(required = true)]
+        sources: Vec<String>,
+        /// Move the merged Toniefiles to the trash, except the one of the merged tag
+        #[arg(long)]
+        remove: bool,
+    },
+    /// Show how much space is used and free on a card and the largest Toniefiles on it
+    Capacity {
+        /// CONTENT folder on the card
+        #[arg(short, long)]
+        content: PathBuf,
+    },
+    /// List Toniefiles under different tags with the same audio or the same audio ID
+    Duplicates {
+        /// CONTENT folder on the card
+        #[arg(short, long)]
+        content: PathBuf,
+    },
+    /// List the files of the Toniebox itself in the `000000*` folders, or extract them
+    SystemFiles {
+        /// CONTENT folder on the card
+        #[arg(short, long)]
+        content: PathBuf,
+        /// Folder to write the audio of the system sounds, and the other files as they are, to
+        #[arg(long)]
+        extract: Option<PathBuf>,
+    },
+    /// List files and folders in CONTENT that are not Toniefiles in tag folders, or repair them
+    Health {
+        /// CONTENT folder on the card
+        #[arg(short, long)]
+        content: PathBuf,
+        /// Kinds of problems to repair, as listed
+        #[arg(long, value_enum, value_delimiter = ',')]
+        repair: Vec<HealthProblem>,
+    },
+    /// List the Toniefiles in the trash, or restore one of them
+    Trash {
+        /// CONTENT folder the Toniefiles were deleted from
+        #[arg(short, long)]
+        content: PathBuf,
+        /// Number of the Toniefile to put back, as listed
+        #[arg(long, conflicts_with = "empty")]
+        restore: Option<usize>,
+        /// Replace the Toniefile that has the tag of the restored one now
+        #[arg(long, requires = "restore")]
+        force: bool,
+        /// Delete all Toniefiles in the trash for good
+        #[arg(long)]
+        empty: bool,
+    },
+    /// Compare the Toniefiles of two CONTENT folders without changing anything
+    Diff {
+        left: PathBuf,
+        right: PathBuf,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// List the differences between two CONTENT folders, or sync some of them
+    Sync {
+        /// First CONTENT folder, e.g. the master copy
+        left: PathBuf,
+        /// Second CONTENT folder, e.g. the card
+        right: PathBuf,
+        /// Numbers of the differences, as listed, to resolve by making the right folder like the
+        /// left one
+        #[arg(long, value_delimiter = ',')]
+        to_right: Vec<usize>,
+        /// Numbers of the differences, as listed, to resolve by making the left folder like the
+        /// right one
+        #[arg(long, value_delimiter = ',')]
+        to_left: Vec<usize>,
+    },
+    /// Write all files of a CONTENT folder to a compressed archive
+    Backup {
+        /// CONTENT folder on the card
+        #[arg(short, long)]
+        content: PathBuf,
+        /// Archive to write, a gzipped tar file
+        archive: PathBuf,
+    },
+    /// Write the files of a backup to a CONTENT folder and check their SHA-1 hashes
+    RestoreBackup {
+        /// CONTENT folder on the card, it must be empty or not exist yet
+        #[arg(short, long)]
+        content: PathBuf,
+        /// Write the files even if the CONTENT folder is not empty
+        #[arg(long)]
+        force: bool,
+        /// Archive written by the backup command
+        archive: PathBuf,
+    },
+    /// Create or update the Toniefiles of a CONTENT folder as described by a manifest
+    Build {
+        /// CONTENT folder to write the Toniefiles to
+        #[arg(short, long)]
+        content: PathBuf,
+        /// What to do when packets of the input files can not be decoded
+        #[arg(long, value_enum, default_value_t = OnDecodeError::Skip)]
+        on_decode_error: OnDecodeError,
+        /// Number of broken packets that is still accepted with `--on-decode-error threshold`
+        #[arg(long, default_value_t = 10)]
+        max_decode_errors: usize,
+        /// Encode all Toniefiles again, even if they are up to date
+        #[arg(long)]
+        force: bool,
+        /// Only print what would be done
+        #[arg(long)]
+        dry_run: bool,
+        /// Manifest file (TOML, or JSON with a .json extension)
+        manifest: PathBuf,
+    },
+}
+
+/// Options for encoding audio files into a Toniefile
+#[derive(Debug, Args)]
+struct EncodeArgs {
+    /// What to do when packets of the input files can not be decoded
+    #[arg(long, value_enum, default_value_t = OnDecodeError::Skip)]
+    on_decode_error: OnDecodeError,
+    /// Number of broken packets that is still accepted with `--on-decode-error threshold`
+    #[arg(long, default_value_t = 10)]
+    max_decode_errors: usize,
+    /// Scale every input file so its loudest sample is at about -1 dBFS
+    #[arg(long)]
+    normalize: bool,
+    /// Seconds of silence between chapters
+    #[arg(long, default_value_t = 0.0)]
+    gap: f64,
+    /// Cut the silence at the start and the end of every input file
+    #[arg(long)]
+    trim_silence: bool,
+    /// Average Opus bitrate in kbit/s, official Toniefiles have 96
+    #[arg(long, default_value_t = OpusSettings::default().bitrate)]
+    bitrate: u32,
+    /// Opus encoder complexity from 0 (fastest) to 10 (best quality)
+    #[arg(long, default_value_t = OpusSettings::default().complexity)]
+    complexity: u8,
+}
+
+impl EncodeArgs {
+    fn options(&self) -> Result<EncodeOptions> {
+        Ok(EncodeOptions {
+            policy: self.on_decode_error.policy(self.max_decode_errors),
+            gap: Duration::try_from_secs_f64(self.gap)?,
+            normalize: self.normalize,
+            trim_silence: self.trim_silence,
+            audio_id: Default::default(),
+            opus: OpusSettings {
+                bitrate: self.bitrate,
+                complexity: self.complexity,
+            },
+        })
+    }
+}
+
+/// Audio ID of a Toniefile
+#[derive(Debug, Args)]
+struct AudioIdArgs {
+    /// `random`, `now` for the current time like official Toniefiles, or an audio ID in decimal
+    /// or in hex with 0x
+    #[arg(long, conflicts_with = "audio_id_from")]
+    audio_id: Option<AudioId>,
+    /// Copy the audio ID of an existing Toniefile
+    #[arg(long, value_name = "TONIEFILE")]
+    audio_id_from: Option<PathBuf>,
+}
+
+impl AudioIdArgs {
+    fn get(self) -> Option<AudioId> {
+        self.audio_id.or(self.audio_id_from.map(AudioId::CopyFrom))
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum OnDecodeError {
+    Skip,
+    Fail,
+    Threshold,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum HealthProblem {
+    EmptyDir,
+    BadDirName,
+    StrayFile,
+    NestedDir,
+    TempLeftover,
+}
+
+impl HealthProblem {
+    fn problem(self) -> Problem {
+        match self {
+            HealthProblem::EmptyDir => Problem::EmptyDir,
+            HealthProblem::BadDirName => Problem::BadDirName,
+            HealthProblem::StrayFile => Problem::StrayFile,
+            HealthProblem::NestedDir => Problem::NestedDir,
+            HealthProblem::TempLeftover => Problem::TempLeftover,
+        }
+    }
+}
+
+impl OnDecodeError {
+    fn policy(self, max_decode_errors: usize) -> DecodeErrorPolicy {
+        match self {
+            OnDecodeError::Skip => DecodeErrorPolicy::Skip,
+            OnDecodeError::Fail => DecodeErrorPolicy::Fail,
+            OnDecodeError::Threshold => DecodeErrorPolicy::FailAbove(max_decode_errors),
+        }
+    }
+}
+
+/// Parse the `--layout` of the chapters command, chapter numbers start at 1
+fn parse_layout(layout: &str) -> Result<Vec<Vec<usize>>> {
+    layout
+        .split(',')
+        .map(|chapter| {
+            chapter
+                .split('+')
+                .map(|n| match n.trim().parse::<usize>() {
+                    Ok(n) if n > 0 => Ok(n - 1),
+                    _ => Err(anyhow!("invalid chapter number {:?}", n)),
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Arrange chapters as described by a parsed `--layout`
+fn apply_layout(chapters: &[Chapter], layout: &[Vec<usize>]) -> Result<Vec<Chapter>> {
+    layout
+        .iter()
+        .map(|parts| {
+            let mut merged = parts
+                .iter()
+                .map(|&i| {
+                    chapters
+                        .get(i)
+                        .cloned()
+                        .ok_or(anyhow!("there is no chapter {}", i + 1))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            while merged.len() > 1 {
+                merge_with_next(&mut merged, 0);
+            }
+            merged.pop().ok_or(anyhow!("empty chapter in layout"))
+        })
+        .collect()
+}
+
+/// Parse chapter numbers like `2-4` or `3` into a range of chapter indices
+fn parse_range(chapters: &str) -> Result<Range<usize>> {
+    let number = |n: &str| match n.trim().parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(anyhow!("invalid chapter number {:?}", n)),
+    };
+    let (first, last) = match chapters.split_once('-') {
+        Some((first, last)) => (number(first)?, number(last)?),
+        None => (number(chapters)?, number(chapters)?),
+    };
+    if first > last {
+        return Err(anyhow!("invalid chapter range {}", chapters));
+    }
+    Ok(first - 1..last)
+}
+
+fn print_chapters(chapters: &[Chapter], options: &EncodeOptions) {
+    for (i, chapter) in chapters.iter().enumerate() {
+        eprintln!(
+            "chapter {}: {} [{}] {}",
+            i + 1,
+            chapter.title,
+            format_duration(chapter.duration),
+            chapter.inputs[0].path.display()
+        );
+    }
+    if let Some(duration) = total_duration(chapters) {
+        let size = options.opus.estimated_size(duration, chapters.len());
+        eprintln!("estimated size: {}", format_size(size));
+    }
+}
+
+/// The official tonies, for their titles and so a drawn audio ID is none of theirs
+///
+/// Without a connection the list is empty.
+fn official_tonies() -> Arc<Vec<Tonie>> {
+    Arc::new(get_tonie_list_online(None).unwrap_or_default())
+}
+
+/// The official tonies if `audio_id` is drawn, otherwise they are not needed
+fn drawn_id_tonies(audio_id: &AudioId) -> Arc<Vec<Tonie>> {
+    if audio_id.is_drawn() {
+        official_tonies()
+    } else {
+        Arc::default()
+    }
+}
+
+fn build(
+    content: PathBuf,
+    manifest_path: PathBuf,
+    policy: DecodeErrorPolicy,
+    force: bool,
+    dry_run: bool,
+    tx: Sender<Action>,
+) -> Result<()> {
+    let manifest = Manifest::load(&manifest_path)?;
+    let steps = plan_build(&manifest, &content, policy, force)?;
+    for tag in unlisted_tags(&manifest, &content)? {
+        eprintln!("{}: not in the manifest, leaving it alone", tag);
+    }
+    let mut failed = 0;
+    let mut tonies = None;
+    for (id, mut step) in (1..).zip(steps) {
+        let target = step.request.target.to_string();
+        eprintln!("{}: {}", target, step.action);
+        if step.action == BuildAction::Skip || dry_run {
+            continue;
+        }
+        print_chapters(&step.request.chapters, &step.request.options);
+        if step.request.options.audio_id.is_drawn() {
+            step.request.tonies = tonies.get_or_insert_with(official_tonies).clone();
+        }
+        match run_request(step.request, JobContext::new(id, tx.clone())) {
+            Ok(report) if !report.is_empty() => println!("{}: {}", target, report),
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("{}: failed: {}", target, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!("{} Toniefiles could not be built", failed));
+    }
+    Ok(())
+}
+
+fn run_command(command: Command) -> Result<()> {
+    let (tx, rx) = crossbeam::channel::unbounded::<Action>();
+    // print what the worker reports, the GUI would do this in its update loop
+    let printer = std::thread::spawn(move || {
+        // several progress events fall into the same 10% step, print each step once per file
+        let mut last_step = None;
+        for action in rx {
+            match action {
+                Action::Job(_, JobEvent::CurrentFileNo(n)) => {
+                    last_step = None;
+                    eprintln!("encoding file {}", n)
+                }
+                Action::CurrentFile(status) if !status.is_empty() => eprintln!("{}", status),
+                Action::Job(id, JobEvent::Progress(p))
+                    if last_step != Some((id, p.file_percent / 10)) =>
+                {
+                    last_step = Some((id, p.file_percent / 10));
+                    eprintln!(
+                        "processed: {}% total: {}% ETA: {}",
+                        p.file_percent,
+                        p.total_percent,
+                        p.eta_string()
+                    )
+                }
+                _ => {}
+            }
+        }
+    });
+    let result = match command {
+        Command::Add {
+            content,
+            tag,
+            encode,
+            audio_id,
+            files,
+        } => {
+            check_tag_id_validity(&tag)?;
+            let options = EncodeOptions {
+                audio_id: audio_id.get().unwrap_or_default(),
+                ..encode.options()?
+            };
+            let chapters = plan_chapters(expand_inputs(&files)?);
+            print_chapters(&chapters, &options);
+            let tonies = drawn_id_tonies(&options.audio_id);
+            let job = JobContext::new(0, tx);
+            add_audio_file(content, chapters, tag, options, &tonies, job).map(|report| {
+                if !report.is_empty() {
+                    println!("{}", report);
+                }
+            })
+        }
+        Command::Append {
+            content,
+            tag,
+            encode,
+            files,
+        } => {
+            let path = tag_path(&content, &tag)?;
+            let options = encode.options()?;
+            let chapters = plan_chapters(expand_inputs(&files)?);
+            print_chapters(&chapters, &options);
+            append_chapters(path, chapters, options, JobContext::new(0, tx)).map(|report| {
+                if !report.is_empty() {
+                    println!("{}", report);
+                }
+            })
+        }
+        Command::Chapters {
+            content,
+            tag,
+            layout,
+        } => {
+            // nothing is encoded, so there is no progress to print
+            drop(tx);
+            let mut chapter_layout = ChapterLayout::load(&tag_path(&content, &tag)?)?;
+            if let Some(layout) = layout {
+                chapter_layout.chapters = parse_layout(&layout)?;
+                chapter_layout.save()?;
+                chapter_layout = ChapterLayout::load(&chapter_layout.path)?;
+            }
+            for (i, duration) in chapter_layout.durations.iter().enumerate() {
+                println!("chapter {}: {}", i + 1, format_duration(Some(*duration)));
+            }
+            Ok(())
+        }
+        Command::Reencode {
+            content,
+            tag,
+            encode,
+            audio_id,
+            layout,
+        } => {
+            let path = tag_path(&content, &tag)?;
+            let options = EncodeOptions {
+                audio_id: audio_id.get().unwrap_or(AudioId::CopyFrom(path.clone())),
+                ..encode.options()?
+            };
+            let mut chapters = plan_chapters(toniefile_inputs(&path)?);
+            if let Some(layout) = layout {
+                chapters = apply_layout(&chapters, &parse_layout(&layout)?)?;
+            }
+            print_chapters(&chapters, &options);
+            let tonies = drawn_id_tonies(&options.audio_id);
+            let job = JobContext::new(0, tx);
+            add_audio_file(content, chapters, tag, options, &tonies, job).map(|report| {
+                if !report.is_empty() {
+                    println!("{}", report);
+                }
+            })
+        }
+        Command::AudioId {
+            content,
+            tag,
+            audio_id,
+        } => {
+            drop(tx);
+            let path = tag_path(&content, &tag)?;
+            if let Some(audio_id) = audio_id.get() {
+                set_audio_id(&path, audio_id.resolve(&drawn_id_tonies(&audio_id))?)?;
+            }
+            let header = Toniefile::parse_header(&mut File::open(&path)?)?;
+            println!("audio ID: {} (0x{:08X})", header.audio_id, header.audio_id);
+            Ok(())
+        }
+        Command::Retag {
+            content,
+            tag,
+            new_tag,
+            force,
+        } => {
+            drop(tx);
+            let replaced = change_tag_id(&content, &tag_path(&content, &tag)?, &new_tag, force)
+                .map_err(|e| match e.downcast::<TagInUse>() {
+                    Ok(e) => anyhow!("{}, use --force to replace it", e),
+                    Err(e) => e,
+                })?;
+            if let Some(entry) = replaced {
+                println!("moved the Toniefile of {} to the trash", entry.tag);
+            }
+            Ok(())
+        }
+        Command::Swap { content, tags } => {
+            drop(tx);
+            swap_toniefiles(
+                &tag_path(&content, &tags[0])?,
+                &tag_path(&content, &tags[1])?,
+            )
+        }
+        Command::Split {
+            content,
+            tag,
+            chapters,
+            new_tag,
+            remove,
+        } => {
+            drop(tx);
+            split_chapters(
+                &tag_path(&content, &tag)?,
+                parse_range(&chapters)?,
+                &content,
+                &new_tag,
+                remove,
+                &official_tonies(),
+            )
+            .map(|path| println!("wrote {}", path.display()))
+        }
+        Command::Merge {
+            content,
+            tag,
+            sources,
+            remove,
+        } => {
+            drop(tx);
+            let sources = sources
+                .iter()
+                .map(|source| tag_path(&content, source))
+                .collect::<Result<Vec<_>>>()?;
+            let dest = tag_path(&content, &tag)?;
+            // a new Toniefile gets a new audio ID
+            let tonies = if sources.contains(&dest) {
+                Arc::default()
+            } else {
+                official_tonies()
+            };
+            let print_removed = |removed: &[TrashEntry]| {
+                for entry in removed {
+                    println!("moved {} to the trash", entry.tag);
+                }
+            };
+            merge_files(&sources, &dest, &content, remove, &tonies)
+                .map(|removed| print_removed(&removed))
+                .inspect_err(|e| {
+                    if let Some(left) = e.downcast_ref::<SourcesLeft>() {
+                        print_removed(&left.removed);
+                    }
+                })
+        }
+        Command::Capacity { content } => {
+            drop(tx);
+            card_usage(&content, &Arc::new(vec![])).map(|usage| {
+                println!(
+                    "total {}, used {}, free {}",
+                    format_size(usage.total),
+                    format_size(usage.used()),
+                    format_size(usage.available)
+                );
+                println!(
+                    "{} Toniefiles with {}, the largest:",
+                    usage.toniefile_count,
+                    format_size(usage.toniefiles)
+                );
+                for file in usage.largest {
+                    println!("{} {:>10}", file.tag, format_size(file.size));
+                }
+            })
+        }
+        Command::Duplicates { content } => {
+            drop(tx);
+            let groups = find_duplicates(&content, &Arc::new(vec![]))?;
+            for group in &groups {
+                println!("{}", group.duplicate);
+                for file in &group.files {
+                    println!("  {} {:>10}", file.tag, format_size(file.size));
+                }
+            }
+            println!(
+                "{} groups, deleting all but one copy would free {}",
+                groups.len(),
+                format_size(groups.iter().map(|g| g.wasted()).sum())
+            );
+            Ok(())
+        }
+        Command::SystemFiles { content, extract } => {
+            let mut files = vec![];
+            populate_system_files(&content, &mut files, &Arc::new(vec![]))?;
+            let name = |file: &Teddyfile| {
+                let path = file.path().strip_prefix(&content).unwrap_or(file.path());
+                path.to_string_lossy().replace(['/', '\\'], "-")
+            };
+            let result = match extract {
+                Some(dir) => fs::create_dir_all(&dir).map_err(Into::into).and_then(|_| {
+                    files.iter().try_for_each(|file| {
+                        extract_to_ogg(file, &dir.join(name(file)), tx.clone())
+                    })
+                }),
+                None => {
+                    for file in &files {
+                        println!("{}  {}", file.path().display(), file.describe());
+                    }
+                    println!("{} system files", files.len());
+                    Ok(())
+                }
+            };
+            drop(tx);
+            result
+        }
+        Command::Health { content, repair } => {
+            drop(tx);
+            let findings = check_health(&content)?;
+            if !repair.is_empty() {
+                let problems: Vec<_> = repair.iter().map(|p| p.problem()).collect();
+                repair_all(&content, &findings, &problems)
+            } else {
+                for problem in HealthProblem::value_variants() {
+                    let paths: Vec<_> = findings
+                        .iter()
+                        .filter(|f| f.problem == problem.problem())
+                        .collect();
+                    if paths.is_empty() {
+                        continue;
+                    }
+                    let name = problem
+                        .to_possible_value()
+                        .map(|v| v.get_name().to_string());
+                    println!("{} ({}):", problem.problem(), name.unwrap_or_default());
+                    for finding in paths {
+                        println!("  {}", finding.path.display());
+                    }
+                }
+                println!("{} problems", findings.len());
+                Ok(())
+            }
+        }
+        Command::Trash {
+            content,
+            restore: number,
+            force,
+            empty,
+        } => {
+            drop(tx);
+            let entries = list_trash(&content)?;
+            if empty {
+                empty_trash(&content)
+            } else if let Some(number) = number {
+                let entry = entries
+                    .get(number.wrapping_sub(1))
+                    .ok_or(anyhow!("there is no Toniefile {} in the trash", number))?;
+                if let Some(replaced) =
+                    restore(&content, entry, force).map_err(|e| match e.downcast::<TagInUse>() {
+                        Ok(e) => anyhow!("{}, use --force to replace it", e),
+                        Err(e) => e,
+                    })?
+                {
+                    println!("moved the Toniefile of {} to the trash", replaced.tag);
+                }
+                Ok(())
+            } else {
+                for (i, entry) in entries.iter().enumerate() {
+                    // files that were not Toniefiles, from a health check repair
+                    let name = match entry.tag.as_str() {
+                        "invalid" => entry.origin.display().to_string(),
+                        _ => entry.tag.clone(),
+                    };
+                    println!(
+                        "{:>3} {} {:>10}  {}",
+                        i + 1,
+                        name,
+                        format_size(entry.size),
+                        entry.age()
+                    );
+                }
+                Ok(())
+            }
+        }
+        Command::Diff { left, right, json } => {
+            drop(tx);
+            let report = diff_report(&left, &right, &official_tonies())?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print!("{}", report.to_text());
+            }
+            Ok(())
+        }
+        Command::Sync {
+            left,
+            right,
+            to_right,
+            to_left,
+        } => {
+            let differences = compare(&left, &right)?;
+            let mut changes = vec![];
+            for (numbers, direction) in [
+                (to_right, Direction::LeftToRight),
+                (to_left, Direction::RightToLeft),
+            ] {
+                for number in numbers {
+                    let difference = differences
+                        .get(number.wrapping_sub(1))
+                        .ok_or(anyhow!("there is no difference {}", number))?;
+                    changes.push((difference.clone(), direction));
+                }
+            }
+            let result = if changes.is_empty() {
+                for (i, difference) in differences.iter().enumerate() {
+                    println!("{:>3} {}", i + 1, difference);
+                }
+                Ok(())
+            } else {
+                apply_all(&left, &right, &changes, &tx)
+            };
+            drop(tx);
+            result
+        }
+        Command::Backup { content, archive } => {
+            let result = backup(&content, &archive, &tx);
+            drop(tx);
+            result.map(|_| ())
+        }
+        Command::RestoreBackup {
+            content,
+            force,
+            archive,
+        } => {
+            let result = restore_backup(&archive, &content, force, &tx);
+            drop(tx);
+            result.map(|_| ())
+        }
+        Command::Build {
+            content,
+            on_decode_error,
+            max_decode_errors,
+            force,
+            dry_run,
+            manifest,
+        } => build(
+            content,
+            manifest,
+            on_decode_error.policy(max_decode_errors),
+            force,
+            dry_run,
+            tx,
+        ),
+    };
+    let _ = printer.join();
+    result
+}
+
+fn main() -> Result<()> {
     // Parse CLI arguments.
     let cli = Cli::parse();
     // Initialize logging.
@@ -16,6 +914,10 @@ fn main() {
         .filter_level(cli.verbose.log_level_filter())
         .init();
 
+    if let Some(command) = cli.command {
+        return run_command(command);
+    }
+
     // Run GUI
     let _app: RustyBench = Default::default();
     let options = eframe::NativeOptions {
@@ -27,4 +929,5 @@ fn main() {
         options,
         Box::new(|cc| Box::new(RustyBench::new(cc))),
     );
+    Ok(())
 }
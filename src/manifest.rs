@@ -0,0 +1,221 @@
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+use serde::Deserialize;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::chapters::plan_chapters;
+use crate::decode_report::DecodeErrorPolicy;
+use crate::index::{fingerprint, BuildIndex};
+use crate::jobs::{JobRequest, JobTarget};
+use crate::options::{EncodeOptions, OpusSettings};
+use crate::playlist::expand_inputs;
+use crate::{check_tag_id_validity, populate_table, tag_path};
+
+/// A declarative description of the Toniefiles in a CONTENT folder
+///
+/// Manifests are written in TOML, or in JSON if the file name ends with `.json`. Relative paths
+/// of input files are relative to the manifest.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    /// default for all entries that do not set it themselves
+    #[serde(default)]
+    pub normalize: bool,
+    /// default gap between chapters in seconds
+    #[serde(default)]
+    pub gap: f64,
+    #[serde(default)]
+    pub trim_silence: bool,
+    /// Opus bitrate in kbit/s
+    pub bitrate: Option<u32>,
+    pub complexity: Option<u8>,
+    #[serde(default, rename = "tonie", alias = "tonies")]
+    pub entries: Vec<ManifestEntry>,
+}
+
+/// One Toniefile of a manifest
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestEntry {
+    pub tag: String,
+    /// audio files, playlists, CUE sheets or folders, like for `rusty_bench add`
+    pub files: Vec<PathBuf>,
+    /// chapter titles in chapter order, chapters without one keep the title from their tags
+    #[serde(default)]
+    pub titles: Vec<String>,
+    pub normalize: Option<bool>,
+    pub gap: Option<f64>,
+    pub trim_silence: Option<bool>,
+    pub bitrate: Option<u32>,
+    pub complexity: Option<u8>,
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let is_json = path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("json"));
+        let mut manifest: Manifest = if is_json {
+            serde_json::from_str(&content)?
+        } else {
+            toml::from_str(&content)?
+        };
+        let base = path.parent().unwrap_or(Path::new(""));
+        for entry in &mut manifest.entries {
+            entry.tag = entry.tag.to_ascii_uppercase();
+            entry.files = entry.files.iter().map(|f| base.join(f)).collect();
+        }
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    fn validate(&self) -> Result<()> {
+        for (i, entry) in self.entries.iter().enumerate() {
+            check_tag_id_validity(&entry.tag)
+                .map_err(|e| anyhow!("invalid tag {}: {}", entry.tag, e))?;
+            if self.entries[..i].iter().any(|e| e.tag == entry.tag) {
+                return Err(anyhow!("tag {} is listed more than once", entry.tag));
+            }
+            if entry.files.is_empty() {
+                return Err(anyhow!("tag {} has no files", entry.tag));
+            }
+            gap_duration(entry.gap.unwrap_or(self.gap))
+                .map_err(|e| anyhow!("tag {}: {}", entry.tag, e))?;
+            self.opus_settings(entry)
+                .validate()
+                .map_err(|e| anyhow!("tag {}: {}", entry.tag, e))?;
+        }
+        Ok(())
+    }
+
+    fn options(&self, entry: &ManifestEntry, policy: DecodeErrorPolicy) -> Result<EncodeOptions> {
+        Ok(EncodeOptions {
+            policy,
+            gap: gap_duration(entry.gap.unwrap_or(self.gap))?,
+            normalize: entry.normalize.unwrap_or(self.normalize),
+            trim_silence: entry.trim_silence.unwrap_or(self.trim_silence),
+            audio_id: Default::default(),
+            opus: self.opus_settings(entry),
+        })
+    }
+
+    fn opus_settings(&self, entry: &ManifestEntry) -> OpusSettings {
+        let default = OpusSettings::default();
+        OpusSettings {
+            bitrate: entry.bitrate.or(self.bitrate).unwrap_or(default.bitrate),
+            complexity: entry
+                .complexity
+                .or(self.complexity)
+                .unwrap_or(default.complexity),
+        }
+    }
+}
+
+fn gap_duration(seconds: f64) -> Result<Duration> {
+    Duration::try_from_secs_f64(seconds).map_err(|_| anyhow!("invalid gap {}", seconds))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildAction {
+    /// there is no Toniefile for the tag yet
+    Create,
+    /// the inputs or options changed since the Toniefile was built, or it was replaced
+    Update,
+    /// the Toniefile was built from the same inputs with the same options
+    Skip,
+}
+
+impl Display for BuildAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildAction::Create => write!(f, "create"),
+            BuildAction::Update => write!(f, "update"),
+            BuildAction::Skip => write!(f, "skip"),
+        }
+    }
+}
+
+/// What has to be done for one entry of a manifest
+#[derive(Debug, Clone)]
+pub struct BuildStep {
+    pub action: BuildAction,
+    pub request: JobRequest,
+}
+
+/// Work out which Toniefiles in `content` have to be created or updated to match the manifest
+///
+/// Toniefiles are skipped if the build index next to `content` says they were built from the same
+/// inputs and the SHA-1 hash of the file on the card still matches. With `force` every Toniefile
+/// is encoded again.
+pub fn plan_build(
+    manifest: &Manifest,
+    content: &Path,
+    policy: DecodeErrorPolicy,
+    force: bool,
+) -> Result<Vec<BuildStep>> {
+    let index = BuildIndex::load(content)?;
+    let mut steps = vec![];
+    for entry in &manifest.entries {
+        let mut chapters = plan_chapters(expand_inputs(&entry.files)?);
+        for (chapter, title) in chapters.iter_mut().zip(&entry.titles) {
+            chapter.title = title.clone();
+        }
+        if entry.titles.len() > chapters.len() {
+            return Err(anyhow!(
+                "tag {} has {} titles for {} chapters",
+                entry.tag,
+                entry.titles.len(),
+                chapters.len()
+            ));
+        }
+        let options = manifest.options(entry, policy)?;
+        let fingerprint = fingerprint(&chapters, &options)?;
+        let action = if !tag_path(content, &entry.tag)?.exists() {
+            BuildAction::Create
+        } else if !force && index.is_unchanged(content, &entry.tag, &fingerprint) {
+            BuildAction::Skip
+        } else {
+            BuildAction::Update
+        };
+        debug!("{}: {}", entry.tag, action);
+        steps.push(BuildStep {
+            action,
+            request: JobRequest {
+                target: JobTarget::Tag {
+                    content: content.to_path_buf(),
+                    tag: entry.tag.clone(),
+                },
+                chapters,
+                options,
+                fingerprint: Some(fingerprint),
+                tonies: Default::default(),
+            },
+        });
+    }
+    let count = |action| steps.iter().filter(|s| s.action == action).count();
+    info!(
+        "build plan: {} to create, {} to update, {} up to date",
+        count(BuildAction::Create),
+        count(BuildAction::Update),
+        count(BuildAction::Skip)
+    );
+    Ok(steps)
+}
+
+/// Tags of the Toniefiles in `content` that the manifest does not know about
+///
+/// The build leaves them alone, but they mean the card does not exactly match the manifest.
+pub fn unlisted_tags(manifest: &Manifest, content: &Path) -> Result<Vec<String>> {
+    let mut files = vec![];
+    populate_table(content, &mut files, &Arc::new(vec![]))?;
+    Ok(files
+        .into_iter()
+        .map(|f| f.tag)
+        .filter(|tag| !manifest.entries.iter().any(|e| &e.tag == tag))
+        .collect())
+}
@@ -0,0 +1,233 @@
+use anyhow::{anyhow, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use toniefile::Toniefile;
+
+use crate::decode_report::DecodeErrorPolicy;
+use crate::taf::BLOCK_SIZE;
+use crate::tonielist::{find_tonie_with_audio_id, Tonie};
+
+/// Sample rate of the audio passed to the Toniefile encoder
+pub const SAMPLE_RATE: usize = 48000;
+/// The Toniefile encoder takes interleaved stereo samples
+pub const CHANNELS: usize = 2;
+
+/// Level of the loudest sample of an input after normalization, about -1 dBFS
+const NORMALIZE_PEAK: f32 = i16::MAX as f32 * 0.891;
+/// Samples up to this level count as silence when trimming, about -54 dBFS
+const SILENCE_LEVEL: u16 = 64;
+/// Bitrates in kbit/s that can be chosen, below music falls apart and above there is nothing
+/// left to hear on the speaker of a Toniebox
+pub const BITRATES: RangeInclusive<u32> = 16..=256;
+/// Complexity of the Opus encoder, from fastest to best quality
+pub const COMPLEXITIES: RangeInclusive<u8> = 0..=10;
+
+/// How the inputs of a Toniefile are turned into audio
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EncodeOptions {
+    pub policy: DecodeErrorPolicy,
+    /// silence inserted between two chapters
+    pub gap: Duration,
+    /// scale every input so its loudest sample is at about -1 dBFS
+    pub normalize: bool,
+    /// cut the silence at the start and the end of every input
+    pub trim_silence: bool,
+    /// audio ID of a new Toniefile, Toniefiles that chapters are appended to keep theirs
+    pub audio_id: AudioId,
+    pub opus: OpusSettings,
+}
+
+/// Settings of the Opus encoder
+///
+/// The frame size can not be chosen, Toniefiles always have frames of 60 ms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpusSettings {
+    /// average bitrate in kbit/s, the encoder uses a variable bitrate
+    pub bitrate: u32,
+    pub complexity: u8,
+}
+
+impl Default for OpusSettings {
+    /// the settings of official Toniefiles
+    fn default() -> Self {
+        Self {
+            bitrate: 96,
+            complexity: 10,
+        }
+    }
+}
+
+impl OpusSettings {
+    pub fn validate(&self) -> Result<()> {
+        if !BITRATES.contains(&self.bitrate) {
+            return Err(anyhow!(
+                "bitrate must be between {} and {} kbit/s",
+                BITRATES.start(),
+                BITRATES.end()
+            ));
+        }
+        if !COMPLEXITIES.contains(&self.complexity) {
+            return Err(anyhow!(
+                "complexity must be between {} and {}",
+                COMPLEXITIES.start(),
+                COMPLEXITIES.end()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Rough size of a Toniefile with this much audio, the real size depends on the audio
+    pub fn estimated_size(&self, duration: Duration, chapters: usize) -> u64 {
+        let audio = duration.as_secs_f64() * self.bitrate as f64 * 1000.0 / 8.0;
+        // Ogg pages take about 1 %, every chapter starts in a new block and the last block of a
+        // chapter is filled up
+        let blocks = (audio * 1.01 / BLOCK_SIZE as f64).ceil() as u64 + chapters as u64;
+        // the header has a block of its own
+        (blocks + 1) * BLOCK_SIZE as u64
+    }
+}
+
+/// Where the audio ID of a Toniefile comes from
+///
+/// The audio ID is what the Toniebox uses to tell Toniefiles apart, official Toniefiles use the
+/// time they were created.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum AudioId {
+    #[default]
+    Random,
+    /// the current time, like official Toniefiles
+    Now,
+    Fixed(u32),
+    /// the audio ID of an existing Toniefile
+    CopyFrom(PathBuf),
+}
+
+impl AudioId {
+    /// Whether a new audio ID is drawn, which must not be the one of an official tonie
+    pub fn is_drawn(&self) -> bool {
+        matches!(self, AudioId::Random | AudioId::Now)
+    }
+
+    /// The audio ID, a drawn one is never the one of a tonie in `tonies`
+    pub fn resolve(&self, tonies: &Arc<Vec<Tonie>>) -> Result<u32> {
+        let taken = |id| {
+            let tonie = find_tonie_with_audio_id(tonies, id);
+            if let Some(tonie) = &tonie {
+                info!("audio ID {} is the one of tonie {}", id, tonie.article);
+            }
+            tonie.is_some()
+        };
+        Ok(match self {
+            AudioId::Random => {
+                let mut id = rand::random();
+                while taken(id) {
+                    id = rand::random();
+                }
+                id
+            }
+            AudioId::Now => {
+                // official Toniefiles use the time they were created, a second later is as
+                // good as now
+                let mut id = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs() as u32)
+                    .unwrap_or_default();
+                while taken(id) {
+                    id += 1;
+                }
+                id
+            }
+            AudioId::Fixed(id) => *id,
+            AudioId::CopyFrom(path) => Toniefile::parse_header(&mut File::open(path)?)?.audio_id,
+        })
+    }
+}
+
+/// Parses `random`, `now` or an audio ID, see [`parse_audio_id`]
+impl FromStr for AudioId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "random" => Ok(AudioId::Random),
+            "now" => Ok(AudioId::Now),
+            id => parse_audio_id(id).map(AudioId::Fixed),
+        }
+    }
+}
+
+/// Parse an audio ID in decimal, like RustyBench shows them, or in hex with a `0x` prefix
+pub fn parse_audio_id(s: &str) -> Result<u32> {
+    let s = s.trim();
+    let id = match s.strip_prefix("0x").or(s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    id.map_err(|_| anyhow!("invalid audio ID {:?}", s))
+}
+
+impl EncodeOptions {
+    /// Interleaved samples of silence for the gap between two chapters
+    pub fn gap_samples(&self) -> Vec<i16> {
+        let frames = (self.gap.as_secs_f64() * SAMPLE_RATE as f64) as usize;
+        vec![0; frames * CHANNELS]
+    }
+}
+
+/// The gain that brings an input with the given peak sample to the normalization level
+pub fn normalize_gain(peak: u16) -> f32 {
+    if peak == 0 {
+        return 1.0;
+    }
+    NORMALIZE_PEAK / peak as f32
+}
+
+pub fn apply_gain(samples: &[i16], gain: f32) -> Vec<i16> {
+    samples
+        .iter()
+        .map(|&s| {
+            (s as f32 * gain)
+                .round()
+                .clamp(i16::MIN as f32, i16::MAX as f32) as i16
+        })
+        .collect()
+}
+
+/// Cuts the silence at the start and the end of an input
+///
+/// Silence in the middle of an input is held back until the input goes on, silence that is still
+/// held back when the input ends is dropped.
+#[derive(Debug, Default)]
+pub struct SilenceTrimmer {
+    started: bool,
+    held_back: Vec<i16>,
+}
+
+impl SilenceTrimmer {
+    /// The samples to encode out of the next interleaved `samples` of the input
+    pub fn trim(&mut self, samples: &[i16]) -> Vec<i16> {
+        let mut trimmed = vec![];
+        for frame in samples.chunks(CHANNELS) {
+            let silent = frame.iter().all(|s| s.unsigned_abs() <= SILENCE_LEVEL);
+            if !self.started {
+                if silent {
+                    continue;
+                }
+                self.started = true;
+            }
+            if silent {
+                self.held_back.extend_from_slice(frame);
+            } else {
+                trimmed.append(&mut self.held_back);
+                trimmed.extend_from_slice(frame);
+            }
+        }
+        trimmed
+    }
+}
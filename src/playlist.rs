@@ -0,0 +1,367 @@
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::folder::collect_folder;
+
+/// Playlists can reference other playlists, stop expanding them at this depth
+const MAX_PLAYLIST_DEPTH: usize = 8;
+
+/// CUE sheets count time in frames of 1/75 second
+const CUE_FRAMES_PER_SECOND: u64 = 75;
+
+/// One chapter of a Toniefile to be encoded
+///
+/// Usually this is a whole audio file, but for CUE sheets it can also be a part of one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChapterInput {
+    pub path: PathBuf,
+    /// start of the chapter in the audio file, `None` for the beginning of the file
+    pub start: Option<Duration>,
+    /// end of the chapter in the audio file, `None` for the end of the file
+    pub end: Option<Duration>,
+    pub title: Option<String>,
+}
+
+impl ChapterInput {
+    pub fn whole_file(path: PathBuf) -> Self {
+        Self {
+            path,
+            start: None,
+            end: None,
+            title: None,
+        }
+    }
+
+    pub fn is_segment(&self) -> bool {
+        self.start.is_some() || self.end.is_some()
+    }
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default()
+}
+
+pub fn is_playlist(path: &Path) -> bool {
+    matches!(extension(path).as_str(), "m3u" | "m3u8" | "pls" | "cue")
+}
+
+/// Expand playlists, CUE sheets and folders in a list of input files into chapters
+///
+/// Audio files are passed through as one chapter each, the order of the inputs is kept.
+pub fn expand_inputs(paths: &[PathBuf]) -> Result<Vec<ChapterInput>> {
+    let mut chapters = vec![];
+    for path in paths {
+        expand_path(path, &mut chapters, 0)?;
+    }
+    if chapters.is_empty() {
+        return Err(anyhow!("no input files"));
+    }
+    Ok(chapters)
+}
+
+fn expand_path(path: &Path, chapters: &mut Vec<ChapterInput>, depth: usize) -> Result<()> {
+    if path.is_dir() {
+        chapters.extend(collect_folder(path)?);
+        return Ok(());
+    }
+    if !is_playlist(path) {
+        chapters.push(ChapterInput::whole_file(path.to_path_buf()));
+        return Ok(());
+    }
+    if depth >= MAX_PLAYLIST_DEPTH {
+        return Err(anyhow!(
+            "playlist {} is nested too deep",
+            path.display()
+        ));
+    }
+    info!("reading playlist {}", path.display());
+    let content = read_text(path)?;
+    let base = path.parent().unwrap_or(Path::new(""));
+    let entries = match extension(path).as_str() {
+        "pls" => parse_pls(&content, base),
+        "cue" => parse_cue(&content, base)?,
+        _ => parse_m3u(&content, base),
+    };
+    if entries.is_empty() {
+        return Err(anyhow!("playlist {} has no entries", path.display()));
+    }
+    for entry in entries {
+        debug!("playlist entry: {:?}", entry);
+        if entry.is_segment() || !is_playlist(&entry.path) {
+            chapters.push(entry);
+        } else {
+            expand_path(&entry.path, chapters, depth + 1)?;
+        }
+    }
+    Ok(())
+}
+
+// playlists are often not UTF-8 (plain .m3u and .pls are usually Latin-1)
+fn read_text(path: &Path) -> Result<String> {
+    let bytes = fs::read(path)?;
+    let text = match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => e.into_bytes().iter().map(|&b| b as char).collect(),
+    };
+    Ok(text.trim_start_matches('\u{feff}').to_string())
+}
+
+fn resolve(base: &Path, entry: &str) -> PathBuf {
+    let entry = entry.trim();
+    let entry = entry.strip_prefix("file://").unwrap_or(entry);
+    // playlists written on windows use backslashes
+    let entry = if std::path::MAIN_SEPARATOR == '/' {
+        entry.replace('\\', "/")
+    } else {
+        entry.to_string()
+    };
+    let path = PathBuf::from(entry);
+    if path.is_absolute() {
+        path
+    } else {
+        base.join(path)
+    }
+}
+
+fn parse_m3u(content: &str, base: &Path) -> Vec<ChapterInput> {
+    let mut entries = vec![];
+    let mut title = None;
+    for line in content.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            // #EXTINF:<seconds>,<title>
+            title = info
+                .split_once(',')
+                .map(|(_, t)| t.trim().to_string())
+                .filter(|t| !t.is_empty());
+        } else if !line.starts_with('#') {
+            entries.push(ChapterInput {
+                title: title.take(),
+                ..ChapterInput::whole_file(resolve(base, line))
+            });
+        }
+    }
+    entries
+}
+
+fn parse_pls(content: &str, base: &Path) -> Vec<ChapterInput> {
+    // entries are numbered and may come in any order
+    let mut entries: Vec<(u32, ChapterInput)> = vec![];
+    let mut titles: Vec<(u32, String)> = vec![];
+    for line in content.lines().map(str::trim) {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let key = key.trim().to_ascii_lowercase();
+        if let Some(n) = key.strip_prefix("file").and_then(|n| n.parse().ok()) {
+            entries.push((n, ChapterInput::whole_file(resolve(base, value))));
+        } else if let Some(n) = key.strip_prefix("title").and_then(|n| n.parse().ok()) {
+            titles.push((n, value.trim().to_string()));
+        }
+    }
+    entries.sort_by_key(|(n, _)| *n);
+    entries
+        .into_iter()
+        .map(|(n, mut entry)| {
+            entry.title = titles
+                .iter()
+                .find(|(t, _)| *t == n)
+                .map(|(_, title)| title.clone());
+            entry
+        })
+        .collect()
+}
+
+// a quoted or unquoted argument of a CUE sheet command
+fn cue_argument(rest: &str) -> String {
+    let rest = rest.trim();
+    if let Some(quoted) = rest.strip_prefix('"') {
+        quoted.split('"').next().unwrap_or_default().to_string()
+    } else {
+        rest.split_whitespace().next().unwrap_or_default().to_string()
+    }
+}
+
+// mm:ss:ff with ff being frames of 1/75 second
+fn parse_cue_time(time: &str) -> Result<Duration> {
+    let parts: Vec<u64> = time
+        .trim()
+        .split(':')
+        .map(|p| p.parse::<u64>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| anyhow!("invalid CUE time {}", time))?;
+    let [min, sec, frames] = parts[..] else {
+        return Err(anyhow!("invalid CUE time {}", time));
+    };
+    Ok(Duration::from_secs(min * 60 + sec)
+        + Duration::from_secs(frames) / CUE_FRAMES_PER_SECOND as u32)
+}
+
+fn parse_cue(content: &str, base: &Path) -> Result<Vec<ChapterInput>> {
+    let mut entries: Vec<ChapterInput> = vec![];
+    let mut file: Option<PathBuf> = None;
+    let mut title: Option<String> = None;
+    let mut in_track = false;
+    for line in content.lines().map(str::trim) {
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        match command.to_ascii_uppercase().as_str() {
+            "FILE" => {
+                file = Some(resolve(base, &cue_argument(rest)));
+                in_track = false;
+            }
+            "TRACK" => {
+                in_track = true;
+                title = None;
+            }
+            "TITLE" if in_track => title = Some(cue_argument(rest)),
+            "INDEX" => {
+                let (number, time) = rest.trim().split_once(char::is_whitespace).unwrap_or(("", ""));
+                // index 01 is the start of the track, 00 is the pregap of it
+                if number.trim() != "01" {
+                    continue;
+                }
+                let path = file
+                    .clone()
+                    .ok_or(anyhow!("CUE sheet has an INDEX before a FILE"))?;
+                let start = parse_cue_time(time)?;
+                if let Some(prev) = entries.last_mut() {
+                    if prev.path == path {
+                        prev.end = Some(start);
+                    }
+                }
+                entries.push(ChapterInput {
+                    path,
+                    start: Some(start),
+                    end: None,
+                    title: title.take(),
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cue_time() {
+        assert_eq!(
+            parse_cue_time("01:02:15").unwrap(),
+            Duration::from_millis(62_200)
+        );
+        assert_eq!(parse_cue_time("00:00:00").unwrap(), Duration::ZERO);
+        assert!(parse_cue_time("01:02").is_err());
+        assert!(parse_cue_time("01:xx:00").is_err());
+    }
+
+    #[test]
+    fn cue_tracks() {
+        let sheet = "PERFORMER \"Someone\"\n\
+                     TITLE \"The Album\"\n\
+                     FILE \"book.mp3\" MP3\n\
+                     \x20 TRACK 01 AUDIO\n\
+                     \x20   TITLE \"One\"\n\
+                     \x20   INDEX 01 00:00:00\n\
+                     \x20 TRACK 02 AUDIO\n\
+                     \x20   TITLE \"Two\"\n\
+                     \x20   INDEX 00 03:58:00\n\
+                     \x20   INDEX 01 04:00:30\n";
+        let entries = parse_cue(sheet, Path::new("/music")).unwrap();
+        assert_eq!(
+            entries,
+            [
+                ChapterInput {
+                    path: PathBuf::from("/music/book.mp3"),
+                    start: Some(Duration::ZERO),
+                    end: Some(Duration::from_millis(240_400)),
+                    title: Some("One".to_string()),
+                },
+                // the last track plays to the end of the file
+                ChapterInput {
+                    path: PathBuf::from("/music/book.mp3"),
+                    start: Some(Duration::from_millis(240_400)),
+                    end: None,
+                    title: Some("Two".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn cue_track_ends_with_its_file() {
+        let sheet = "FILE a.wav WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n\
+                     FILE b.wav WAVE\nTRACK 02 AUDIO\nINDEX 01 00:00:00\n";
+        let entries = parse_cue(sheet, Path::new("/music")).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].end, None);
+        assert_eq!(entries[1].path, PathBuf::from("/music/b.wav"));
+        assert!(parse_cue("TRACK 01 AUDIO\nINDEX 01 00:00:00\n", Path::new("/music")).is_err());
+    }
+
+    #[test]
+    fn m3u_entries() {
+        let playlist = "#EXTM3U\n\
+                        # a comment\n\
+                        \n\
+                        #EXTINF:123,First Title\n\
+                        01 first.mp3\n\
+                        sub\\02 second.mp3\n\
+                        /abs/third.mp3\n\
+                        file:///abs/fourth.mp3\n";
+        let entries = parse_m3u(playlist, Path::new("/music"));
+        let paths: Vec<_> = entries.iter().map(|e| e.path.clone()).collect();
+        assert_eq!(
+            paths,
+            [
+                PathBuf::from("/music/01 first.mp3"),
+                PathBuf::from("/music/sub/02 second.mp3"),
+                PathBuf::from("/abs/third.mp3"),
+                PathBuf::from("/abs/fourth.mp3"),
+            ]
+        );
+        assert_eq!(entries[0].title.as_deref(), Some("First Title"));
+        assert_eq!(entries[1].title, None);
+    }
+
+    #[test]
+    fn pls_entries() {
+        let playlist = "[playlist]\n\
+                        ; a comment\n\
+                        File2=second.mp3\n\
+                        Title2=Second\n\
+                        File1=../first.mp3\n\
+                        NumberOfEntries=2\n\
+                        Version=2\n";
+        let entries = parse_pls(playlist, Path::new("/music"));
+        assert_eq!(
+            entries,
+            [
+                ChapterInput::whole_file(PathBuf::from("/music/../first.mp3")),
+                ChapterInput {
+                    title: Some("Second".to_string()),
+                    ..ChapterInput::whole_file(PathBuf::from("/music/second.mp3"))
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn text_without_bom() {
+        let path = std::env::temp_dir().join(format!("rusty_bench_bom_{}.m3u", std::process::id()));
+        fs::write(&path, b"\xef\xbb\xbfone.mp3\n").unwrap();
+        let utf8 = read_text(&path);
+        // not UTF-8, read as Latin-1
+        fs::write(&path, b"caf\xe9.mp3\n").unwrap();
+        let latin1 = read_text(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(utf8.unwrap(), "one.mp3\n");
+        assert_eq!(latin1.unwrap(), "café.mp3\n");
+    }
+}
@@ -0,0 +1,89 @@
+use std::time::{Duration, Instant};
+
+/// Progress of an encoding job as displayed in the messages panel
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Progress {
+    /// progress of the input file that is currently encoded, in percent
+    pub file_percent: u64,
+    /// progress over all input files of the job, in percent
+    pub total_percent: u64,
+    /// estimated time until the whole job is done
+    pub eta: Option<Duration>,
+}
+
+impl Progress {
+    pub fn is_running(&self) -> bool {
+        self.file_percent > 0 || self.total_percent > 0
+    }
+
+    /// Format the ETA as `mm:ss`, or `--:--` if it is not known yet
+    pub fn eta_string(&self) -> String {
+        match self.eta {
+            Some(eta) => {
+                let secs = eta.as_secs();
+                format!("{:02}:{:02}", secs / 60, secs % 60)
+            }
+            None => "--:--".to_string(),
+        }
+    }
+}
+
+/// Keeps track of the overall progress of a job with several input files
+///
+/// Every input file is weighted by its size in bytes, so a long audio file counts more than a short
+/// one.
+#[derive(Debug)]
+pub struct ProgressTracker {
+    start_time: Instant,
+    sizes: Vec<u64>,
+    total_size: u64,
+    current: usize,
+    done_size: u64,
+}
+
+impl ProgressTracker {
+    pub fn new(sizes: Vec<u64>) -> Self {
+        // files of size 0 would never contribute, so weigh them at least 1 byte
+        let sizes: Vec<u64> = sizes.into_iter().map(|s| s.max(1)).collect();
+        let total_size = sizes.iter().sum();
+        Self {
+            start_time: Instant::now(),
+            sizes,
+            total_size,
+            current: 0,
+            done_size: 0,
+        }
+    }
+
+    /// Mark the current input file as done and move on to the next one
+    pub fn next_file(&mut self) {
+        if let Some(size) = self.sizes.get(self.current) {
+            self.done_size += size;
+            self.current += 1;
+        }
+    }
+
+    /// Compute the progress of the job from the fraction (0.0 - 1.0) of the current file that is done
+    pub fn progress(&self, file_fraction: f64) -> Progress {
+        let file_fraction = file_fraction.clamp(0.0, 1.0);
+        let current_size = self.sizes.get(self.current).copied().unwrap_or_default();
+        let total_fraction = if self.total_size > 0 {
+            (self.done_size as f64 + current_size as f64 * file_fraction) / self.total_size as f64
+        } else {
+            0.0
+        };
+        let eta = if total_fraction > 0.0 {
+            let elapsed = self.start_time.elapsed().as_secs_f64();
+            Some(Duration::from_secs_f64(
+                (elapsed / total_fraction - elapsed).max(0.0),
+            ))
+        } else {
+            None
+        };
+        Progress {
+            file_percent: (file_fraction * 100.0) as u64,
+            total_percent: (total_fraction * 100.0) as u64,
+            eta,
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use anyhow::{anyhow, Result};
+use crossbeam::channel::Sender;
+use log::{error, info, warn};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::index::to_hex;
+use crate::tonielist::Tonie;
+use crate::trash::move_to_trash;
+use crate::ui::Action;
+use crate::{
+    change_tag_id, populate_table, remove_dir_if_empty, replace_toniefile, tag_path, Teddyfile,
+    TEMP_EXTENSION,
+};
+
+/// How the Toniefiles of two CONTENT folders differ
+///
+/// Toniefiles are compared by the SHA-1 hash in their header, so only the audio counts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Difference {
+    /// only the left folder has a Toniefile for the tag
+    OnlyLeft { tag: String, sha1: String },
+    /// only the right folder has a Toniefile for the tag
+    OnlyRight { tag: String, sha1: String },
+    /// both folders have a Toniefile for the tag, with different audio
+    Changed {
+        tag: String,
+        left_sha1: String,
+        right_sha1: String,
+    },
+    /// the same audio is at different tags, and neither folder has a Toniefile for the tag of
+    /// the other one
+    Retagged {
+        left_tag: String,
+        right_tag: String,
+        sha1: String,
+    },
+}
+
+/// Which way a difference is resolved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// make the right folder like the left one
+    LeftToRight,
+    /// make the left folder like the right one
+    RightToLeft,
+}
+
+impl Difference {
+    /// The tag the difference is sorted by
+    pub fn tag(&self) -> &str {
+        match self {
+            Difference::OnlyLeft { tag, .. }
+            | Difference::OnlyRight { tag, .. }
+            | Difference::Changed { tag, .. } => tag,
+            Difference::Retagged { left_tag, .. } => left_tag,
+        }
+    }
+
+    /// What resolving the difference in `direction` does, with names for both folders
+    pub fn describe(&self, direction: Direction, left: &str, right: &str) -> String {
+        let (from, to) = match direction {
+            Direction::LeftToRight => (left, right),
+            Direction::RightToLeft => (right, left),
+        };
+        match (self, direction) {
+            (Difference::OnlyLeft { tag, .. }, Direction::LeftToRight)
+            | (Difference::OnlyRight { tag, .. }, Direction::RightToLeft) => {
+                format!("copy {} to {}", tag, to)
+            }
+            (Difference::OnlyLeft { tag, .. }, Direction::RightToLeft)
+            | (Difference::OnlyRight { tag, .. }, Direction::LeftToRight) => {
+                format!("delete {} from {}", tag, to)
+            }
+            (Difference::Changed { tag, .. }, _) => {
+                format!("replace {} on {} with the one from {}", tag, to, from)
+            }
+            (
+                Difference::Retagged {
+                    left_tag,
+                    right_tag,
+                    ..
+                },
+                Direction::LeftToRight,
+            ) => format!("move {} to {} on {}", right_tag, left_tag, to),
+            (
+                Difference::Retagged {
+                    left_tag,
+                    right_tag,
+                    ..
+                },
+                Direction::RightToLeft,
+            ) => format!("move {} to {} on {}", left_tag, right_tag, to),
+        }
+    }
+}
+
+impl Display for Difference {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Difference::OnlyLeft { tag, .. } => write!(f, "{} only on the left", tag),
+            Difference::OnlyRight { tag, .. } => write!(f, "{} only on the right", tag),
+            Difference::Changed { tag, .. } => write!(f, "{} has different audio", tag),
+            Difference::Retagged {
+                left_tag,
+                right_tag,
+                ..
+            } => write!(f, "{} on the left is {} on the right", left_tag, right_tag),
+        }
+    }
+}
+
+/// The valid Toniefiles of a CONTENT folder, by tag ID
+pub(crate) fn valid_toniefiles(
+    content: &Path,
+    tonies: &Arc<Vec<Tonie>>,
+) -> Result<BTreeMap<String, Teddyfile>> {
+    let mut files = vec![];
+    populate_table(content, &mut files, tonies)?;
+    let mut valid = BTreeMap::new();
+    for file in files {
+        if !file.is_valid {
+            warn!("skipping {}, its header is broken", file.path.display());
+            continue;
+        }
+        valid.insert(file.tag.clone(), file);
+    }
+    Ok(valid)
+}
+
+/// Header hashes of Toniefiles, by tag ID
+pub(crate) fn toniefile_hashes(files: &BTreeMap<String, Teddyfile>) -> BTreeMap<String, String> {
+    files
+        .iter()
+        .map(|(tag, file)| (tag.clone(), to_hex(&file.hash)))
+        .collect()
+}
+
+/// Find out how the Toniefiles of two CONTENT folders differ, sorted by tag
+pub fn compare(left: &Path, right: &Path) -> Result<Vec<Difference>> {
+    let tonies = Arc::new(vec![]);
+    let left = toniefile_hashes(&valid_toniefiles(left, &tonies)?);
+    let right = toniefile_hashes(&valid_toniefiles(right, &tonies)?);
+    Ok(compare_hashes(&left, &right))
+}
+
+/// The differences between two sets of Toniefiles, given as header hashes by tag ID
+pub(crate) fn compare_hashes(
+    left: &BTreeMap<String, String>,
+    right: &BTreeMap<String, String>,
+) -> Vec<Difference> {
+    let mut differences = vec![];
+    let mut only_right: Vec<(&String, &String)> = right
+        .iter()
+        .filter(|(tag, _)| !left.contains_key(*tag))
+        .collect();
+    for (tag, sha1) in left {
+        match right.get(tag) {
+            Some(right_sha1) if right_sha1 == sha1 => {}
+            Some(right_sha1) => differences.push(Difference::Changed {
+                tag: tag.clone(),
+                left_sha1: sha1.clone(),
+                right_sha1: right_sha1.clone(),
+            }),
+            None => match only_right.iter().position(|(_, s)| *s == sha1) {
+                Some(i) => {
+                    let (right_tag, _) = only_right.remove(i);
+                    differences.push(Difference::Retagged {
+                        left_tag: tag.clone(),
+                        right_tag: right_tag.clone(),
+                        sha1: sha1.clone(),
+                    });
+                }
+                None => differences.push(Difference::OnlyLeft {
+                    tag: tag.clone(),
+                    sha1: sha1.clone(),
+                }),
+            },
+        }
+    }
+    differences.extend(
+        only_right
+            .into_iter()
+            .map(|(tag, sha1)| Difference::OnlyRight {
+                tag: tag.clone(),
+                sha1: sha1.clone(),
+            }),
+    );
+    differences.sort_by(|a, b| a.tag().cmp(b.tag()));
+    differences
+}
+
+/// Resolve a difference between two CONTENT folders
+///
+/// Toniefiles that are deleted or replaced go to the trash of their folder.
+pub fn apply(
+    left: &Path,
+    right: &Path,
+    difference: &Difference,
+    direction: Direction,
+) -> Result<()> {
+    let (from, to) = match direction {
+        Direction::LeftToRight => (left, right),
+        Direction::RightToLeft => (right, left),
+    };
+    match (difference, direction) {
+        (Difference::OnlyLeft { tag, .. }, Direction::LeftToRight)
+        | (Difference::OnlyRight { tag, .. }, Direction::RightToLeft)
+        | (Difference::Changed { tag, .. }, _) => copy_toniefile(from, to, tag),
+        (Difference::OnlyLeft { tag, .. }, Direction::RightToLeft)
+        | (Difference::OnlyRight { tag, .. }, Direction::LeftToRight) => {
+            let path = tag_path(to, tag)?;
+            move_to_trash(to, &path)?;
+            if let Some(parent) = path.parent() {
+                remove_dir_if_empty(parent)?;
+            }
+            Ok(())
+        }
+        (
+            Difference::Retagged {
+                left_tag,
+                right_tag,
+                ..
+            },
+            _,
+        ) => {
+            let (tag, old_tag) = match direction {
+                Direction::LeftToRight => (left_tag, right_tag),
+                Direction::RightToLeft => (right_tag, left_tag),
+            };
+            change_tag_id(to, &tag_path(to, old_tag)?, tag, false).map(|_| ())
+        }
+    }
+}
+
+/// Copy the Toniefile for `tag` to another CONTENT folder, replacing the one that is there
+fn copy_toniefile(from: &Path, to: &Path, tag: &str) -> Result<()> {
+    let source = tag_path(from, tag)?;
+    let dest = tag_path(to, tag)?;
+    let dir = dest
+        .parent()
+        .ok_or(anyhow!("{} has no parent folder", dest.display()))?;
+    fs::create_dir_all(dir)?;
+    // copy next to the destination first, the folders may be on different filesystems
+    let temp = dest.with_extension(TEMP_EXTENSION);
+    let result = fs::copy(&source, &temp)
+        .map_err(Into::into)
+        .and_then(|_| replace_toniefile(to, &temp, &dest));
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp);
+        let _ = remove_dir_if_empty(dir);
+        return Err(e);
+    }
+    info!("copied {} to {}", source.display(), dest.display());
+    Ok(())
+}
+
+/// Resolve several differences, the ones that fail are logged and skipped
+pub fn apply_all(
+    left: &Path,
+    right: &Path,
+    changes: &[(Difference, Direction)],
+    status: &Sender<Action>,
+) -> Result<()> {
+    let mut failed = 0;
+    for (i, (difference, direction)) in changes.iter().enumerate() {
+        status.send(Action::CurrentFile(format!(
+            "Syncing {} of {}: {}",
+            i + 1,
+            changes.len(),
+            difference.describe(*direction, "left", "right")
+        )))?;
+        if let Err(e) = apply(left, right, difference, *direction) {
+            error!("{}: {}", difference, e);
+            failed += 1;
+        }
+    }
+    status.send(Action::CurrentFile("".to_string()))?;
+    if failed > 0 {
+        return Err(anyhow!(
+            "{} of {} changes could not be synced",
+            failed,
+            changes.len()
+        ));
+    }
+    Ok(())
+}
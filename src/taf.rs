@@ -0,0 +1,1053 @@
+use anyhow::{anyhow, Result};
+use audiopus::coder::Encoder;
+use audiopus::{Application, Channels, SampleRate};
+use log::{debug, error, info};
+use prost::Message;
+use sha1::{Digest, Sha1};
+use std::fmt::{Display, Formatter};
+use std::fs::{self, File};
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use toniefile::toniehead::TonieboxAudioFileHeader;
+use toniefile::Toniefile;
+
+use crate::options::{AudioId, CHANNELS, SAMPLE_RATE};
+use crate::playlist::ChapterInput;
+use crate::tonielist::Tonie;
+use crate::trash::{move_to_trash, TrashEntry};
+use crate::{check_not_system, remove_dir_if_empty, replace_toniefile, tag_path, TEMP_EXTENSION};
+
+/// The audio data of a Toniefile is organized in blocks, every chapter starts at the beginning of
+/// one
+pub const BLOCK_SIZE: usize = 4096;
+/// The Toniebox can not handle more chapters
+pub const MAX_CHAPTERS: usize = 100;
+/// Every Opus packet of a Toniefile holds 60 ms of audio
+pub const FRAME_SAMPLES: usize = SAMPLE_RATE * 60 / 1000;
+
+pub(crate) const PAGE_HEADER_SIZE: usize = 27;
+const PAGE_CONTINUED: u8 = 0x01;
+const PAGE_BOS: u8 = 0x02;
+pub(crate) const PAGE_EOS: u8 = 0x04;
+
+lazy_static! {
+    // CRC-32 of Ogg pages, polynomial 0x04c11db7 without reflection
+    static ref CRC_TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut crc = (i as u32) << 24;
+            for _ in 0..8 {
+                crc = if crc & 0x8000_0000 != 0 {
+                    (crc << 1) ^ 0x04c1_1db7
+                } else {
+                    crc << 1
+                };
+            }
+            *entry = crc;
+        }
+        table
+    };
+}
+
+/// Bytes a packet of `len` bytes takes in an Ogg page, with its lacing values
+pub(crate) fn laced_len(len: usize) -> usize {
+    len + len / 255 + 1
+}
+
+/// Length of the packet that takes exactly `space` bytes in an Ogg page
+///
+/// There is none for multiples of 256, one more byte of packet needs two more bytes there.
+pub(crate) fn packet_len_for(space: usize) -> Option<usize> {
+    let len = space.checked_sub(space / 256 + 1)?;
+    (laced_len(len) == space).then_some(len)
+}
+
+fn ogg_crc(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, &b| {
+        (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ b) as usize]
+    })
+}
+
+/// One Ogg page of a Toniefile
+#[derive(Debug, Clone)]
+pub struct OggPage {
+    data: Vec<u8>,
+    /// number of samples the packets ending on this page hold
+    samples: u64,
+}
+
+impl OggPage {
+    pub(crate) fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < PAGE_HEADER_SIZE || &buf[..4] != b"OggS" {
+            return Err(anyhow!("no Ogg page found"));
+        }
+        let header_len = PAGE_HEADER_SIZE + buf[26] as usize;
+        let body_len: usize = buf
+            .get(PAGE_HEADER_SIZE..header_len)
+            .ok_or(anyhow!("Ogg page is truncated"))?
+            .iter()
+            .map(|&l| l as usize)
+            .sum();
+        let data = buf
+            .get(..header_len + body_len)
+            .ok_or(anyhow!("Ogg page is truncated"))?
+            .to_vec();
+        Ok(Self { data, samples: 0 })
+    }
+
+    /// A page with complete packets, at most 255 lacing values of them
+    pub(crate) fn with_packets<P: AsRef<[u8]>>(packets: &[P], samples: u64) -> Self {
+        let mut data = b"OggS".to_vec();
+        // version, header type, granule position, serial, sequence number and CRC are set later
+        data.resize(PAGE_HEADER_SIZE - 1, 0);
+        let mut lacing = vec![];
+        for packet in packets {
+            let len = packet.as_ref().len();
+            lacing.extend(std::iter::repeat_n(255u8, len / 255));
+            lacing.push((len % 255) as u8);
+        }
+        data.push(lacing.len() as u8);
+        data.extend(lacing);
+        for packet in packets {
+            data.extend_from_slice(packet.as_ref());
+        }
+        Self { data, samples }
+    }
+
+    pub(crate) fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub(crate) fn set_flag(&mut self, flag: u8, set: bool) {
+        if set {
+            self.data[5] |= flag;
+        } else {
+            self.data[5] &= !flag;
+        }
+    }
+
+    fn has_flag(&self, flag: u8) -> bool {
+        self.data[5] & flag != 0
+    }
+
+    pub(crate) fn granule(&self) -> u64 {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&self.data[6..14]);
+        u64::from_le_bytes(bytes)
+    }
+
+    pub(crate) fn set_granule(&mut self, granule: u64) {
+        self.data[6..14].copy_from_slice(&granule.to_le_bytes());
+    }
+
+    pub(crate) fn set_serial(&mut self, serial: u32) {
+        self.data[14..18].copy_from_slice(&serial.to_le_bytes());
+    }
+
+    pub(crate) fn set_sequence(&mut self, sequence: u32) {
+        self.data[18..22].copy_from_slice(&sequence.to_le_bytes());
+    }
+
+    /// The packets in this page, pages of Toniefiles only hold complete packets
+    fn packets(&self) -> Vec<&[u8]> {
+        let header_len = PAGE_HEADER_SIZE + self.data[26] as usize;
+        let mut packets = vec![];
+        let mut start = header_len;
+        let mut len = 0;
+        for &lacing in &self.data[PAGE_HEADER_SIZE..header_len] {
+            len += lacing as usize;
+            if lacing < 255 {
+                packets.push(&self.data[start..start + len]);
+                start += len;
+                len = 0;
+            }
+        }
+        packets
+    }
+
+    pub(crate) fn update_crc(&mut self) {
+        self.data[22..26].fill(0);
+        let crc = ogg_crc(&self.data);
+        self.data[22..26].copy_from_slice(&crc.to_le_bytes());
+    }
+}
+
+/// The pages of one block of a Toniefile
+///
+/// Toniefiles have exactly one page per block, but blocks with more pages are read as well.
+pub type Block = Vec<OggPage>;
+
+fn block_len(block: &Block) -> usize {
+    block.iter().map(OggPage::len).sum()
+}
+
+/// A chapter of a Toniefile as the blocks it is made of
+#[derive(Debug, Clone, Default)]
+pub struct TafChapter {
+    pub blocks: Vec<Block>,
+}
+
+impl TafChapter {
+    /// Playing time of the chapter
+    pub fn samples(&self) -> u64 {
+        self.blocks.iter().flatten().map(|p| p.samples).sum()
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.samples() as f64 / SAMPLE_RATE as f64)
+    }
+}
+
+/// A Toniefile split into its header, its Opus header pages and the blocks of audio pages
+///
+/// This allows to rearrange the audio of Toniefiles without encoding it again.
+#[derive(Debug, Clone)]
+pub struct Taf {
+    pub header: TonieboxAudioFileHeader,
+    /// the OpusHead and OpusTags pages
+    head: Vec<OggPage>,
+    /// the first block is shorter than [`BLOCK_SIZE`] by the length of the head pages
+    blocks: Vec<Block>,
+    /// whether the last page is marked as end of stream
+    eos: bool,
+}
+
+impl Taf {
+    pub fn read(path: &Path) -> Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+            .map_err(|e| anyhow!("can not read Toniefile {}: {}", path.display(), e))
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        let header = Toniefile::parse_header(&mut Cursor::new(buf))?;
+        let audio = &buf[BLOCK_SIZE.min(buf.len())..];
+
+        let mut head = vec![];
+        let mut blocks: Vec<Block> = vec![];
+        let mut pos = 0;
+        let mut granule = 0;
+        let mut eos = false;
+        while pos < audio.len() {
+            let mut page = OggPage::parse(&audio[pos..])
+                .map_err(|e| anyhow!("at {:#x}: {}", BLOCK_SIZE + pos, e))?;
+            let block = pos / BLOCK_SIZE;
+            if (pos + page.len() - 1) / BLOCK_SIZE != block {
+                return Err(anyhow!(
+                    "Ogg page at {:#x} crosses a block boundary",
+                    BLOCK_SIZE + pos
+                ));
+            }
+            pos += page.len();
+            // the Opus header pages have no audio and are all in front of the first audio page
+            if blocks.is_empty() && page.granule() == 0 {
+                head.push(page);
+                continue;
+            }
+            if page.has_flag(PAGE_CONTINUED) || page.granule() == u64::MAX {
+                return Err(anyhow!("Opus packets that span pages are not supported"));
+            }
+            page.samples = page
+                .granule()
+                .checked_sub(granule)
+                .ok_or(anyhow!("granule positions are not increasing"))?;
+            granule = page.granule();
+            eos = page.has_flag(PAGE_EOS);
+            if blocks.len() <= block {
+                blocks.resize(block + 1, vec![]);
+            }
+            blocks[block].push(page);
+        }
+        if head.is_empty() || blocks.is_empty() {
+            return Err(anyhow!("no audio found"));
+        }
+        if blocks.iter().any(|b| b.is_empty()) {
+            return Err(anyhow!("audio blocks without Ogg pages"));
+        }
+
+        let taf = Self {
+            header,
+            head,
+            blocks,
+            eos,
+        };
+        taf.chapter_ranges()?;
+        Ok(taf)
+    }
+
+    fn chapter_ranges(&self) -> Result<Vec<Range<usize>>> {
+        let mut starts = self.header.track_page_nums.clone();
+        if starts.first() != Some(&0) {
+            starts.insert(0, 0);
+        }
+        let mut ranges = vec![];
+        for (i, &start) in starts.iter().enumerate() {
+            let end = starts
+                .get(i + 1)
+                .map(|&e| e as usize)
+                .unwrap_or(self.blocks.len());
+            if start as usize >= end || end > self.blocks.len() {
+                return Err(anyhow!(
+                    "invalid chapter pages {:?}",
+                    self.header.track_page_nums
+                ));
+            }
+            ranges.push(start as usize..end);
+        }
+        Ok(ranges)
+    }
+
+    /// Number of samples at the start of the audio that the decoder has to drop, from the OpusHead
+    pub fn pre_skip(&self) -> u64 {
+        self.head
+            .first()
+            .and_then(|page| page.packets().first().and_then(|p| p.get(10..12)))
+            .map(|b| u16::from_le_bytes([b[0], b[1]]) as u64)
+            .unwrap_or_default()
+    }
+
+    /// The Opus packets of the audio
+    pub fn packets(&self) -> impl Iterator<Item = &[u8]> {
+        self.blocks.iter().flatten().flat_map(OggPage::packets)
+    }
+
+    /// The Opus packets from the start of the last chapter that begins at or before `sample`,
+    /// together with the position of that chapter
+    pub fn packets_from(&self, sample: u64) -> (u64, impl Iterator<Item = &[u8]>) {
+        let mut start = (0, 0);
+        let mut position = 0;
+        for range in self.chapter_ranges().unwrap_or_default() {
+            if position > sample {
+                break;
+            }
+            start = (range.start, position);
+            position += self.blocks[range]
+                .iter()
+                .flatten()
+                .map(|p| p.samples)
+                .sum::<u64>();
+        }
+        let packets = self.blocks[start.0..]
+            .iter()
+            .flatten()
+            .flat_map(OggPage::packets);
+        (start.1, packets)
+    }
+
+    /// Playing time of the audio, including the pre-skip
+    pub fn samples(&self) -> u64 {
+        self.blocks.iter().flatten().map(|p| p.samples).sum()
+    }
+
+    /// The chapters of the Toniefile with their audio blocks
+    pub fn chapters(&self) -> Vec<TafChapter> {
+        self.chapter_ranges()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|range| TafChapter {
+                blocks: self.blocks[range].to_vec(),
+            })
+            .collect()
+    }
+
+    /// Build a Toniefile from chapters taken from this or other Toniefiles
+    ///
+    /// The Opus header and the audio ID are taken from this Toniefile. The pages are renumbered,
+    /// their granule positions are recalculated and the header gets the new chapter list and hash.
+    pub fn rebuild(&self, chapters: Vec<TafChapter>) -> Result<Vec<u8>> {
+        self.rebuild_with_id(chapters, self.header.audio_id)
+    }
+
+    pub fn rebuild_with_id(&self, chapters: Vec<TafChapter>, audio_id: u32) -> Result<Vec<u8>> {
+        if chapters.is_empty() || chapters.iter().any(|c| c.blocks.is_empty()) {
+            return Err(anyhow!("a Toniefile needs at least one chapter with audio"));
+        }
+        if chapters.len() > MAX_CHAPTERS {
+            return Err(anyhow!(
+                "a Toniefile can not have more than {} chapters",
+                MAX_CHAPTERS
+            ));
+        }
+        let head_len: usize = self.head.iter().map(OggPage::len).sum();
+        let first_block_len = BLOCK_SIZE - head_len % BLOCK_SIZE;
+
+        let mut silence = SilenceSource::default();
+        let mut blocks: Vec<OggPage> = vec![];
+        let mut track_page_nums = vec![];
+        for (chapter_no, chapter) in chapters.into_iter().enumerate() {
+            track_page_nums.push(if chapter_no == 0 {
+                0
+            } else {
+                blocks.len() as u32
+            });
+            for (block_no, block) in chapter.blocks.into_iter().enumerate() {
+                if blocks.is_empty() && block_len(&block) > first_block_len {
+                    // the first block shares its space with the Opus header pages, if it came
+                    // from somewhere else it may not fit in there
+                    debug!("filling the first block with silence");
+                    blocks.push(silence.page(first_block_len)?);
+                }
+                let free = if blocks.is_empty() {
+                    first_block_len
+                } else {
+                    BLOCK_SIZE
+                };
+                let len = block_len(&block);
+                if len > free {
+                    return Err(anyhow!("block of {} bytes does not fit in {}", len, free));
+                }
+                if len == free && block.len() == 1 {
+                    blocks.extend(block);
+                } else {
+                    // first blocks of other Toniefiles get the silence in front, so it ends up
+                    // before the chapter starts, short last blocks get it at the end
+                    debug!("filling {} bytes of a block with silence", free - len);
+                    blocks.push(silence.fill(&block, free, block_no == 0)?);
+                }
+            }
+        }
+
+        // renumber the pages as one continuous stream
+        let mut head = self.head.clone();
+        let mut audio: Vec<&mut OggPage> = blocks.iter_mut().collect();
+        let mut granule = 0;
+        for page in audio.iter_mut() {
+            granule += page.samples;
+            page.set_granule(granule);
+        }
+        let page_count = head.len() + audio.len();
+        for (sequence, page) in head.iter_mut().chain(audio).enumerate() {
+            page.set_serial(audio_id);
+            page.set_sequence(sequence as u32);
+            page.set_flag(PAGE_BOS, sequence == 0);
+            page.set_flag(PAGE_EOS, self.eos && sequence + 1 == page_count);
+            page.update_crc();
+        }
+
+        let mut data: Vec<u8> = head
+            .iter()
+            .map(|p| p.data.as_slice())
+            .collect::<Vec<_>>()
+            .concat();
+        for page in &blocks {
+            data.extend_from_slice(&page.data);
+        }
+        let header = TonieboxAudioFileHeader {
+            sha1_hash: Sha1::digest(&data).to_vec(),
+            num_bytes: data.len() as u64,
+            audio_id,
+            track_page_nums,
+            fill: vec![],
+        };
+        let mut file = encode_header(header)?;
+        file.extend(data);
+        Ok(file)
+    }
+}
+
+/// Encode a Toniefile header, filled up to the size of a block
+pub(crate) fn encode_header(mut header: TonieboxAudioFileHeader) -> Result<Vec<u8>> {
+    let proto_size = BLOCK_SIZE - 4;
+    header.fill = vec![];
+    // - 1 because the length of the fill needs one more byte when it gets longer than 127
+    let fill = proto_size
+        .checked_sub(header.encoded_len() + 1)
+        .ok_or(anyhow!("chapter list is too long for the header"))?;
+    header.fill = vec![0; fill];
+    if header.encoded_len() != proto_size {
+        return Err(anyhow!("can not fill the header to {} bytes", proto_size));
+    }
+    let mut buf = (proto_size as u32).to_be_bytes().to_vec();
+    header.encode(&mut buf)?;
+    Ok(buf)
+}
+
+/// Write a Toniefile next to `path` and replace `path` with it when it is complete
+///
+/// The Toniefile that was at `path` goes to the trash of its CONTENT folder.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    check_not_system(path)?;
+    let content = path
+        .parent()
+        .and_then(Path::parent)
+        .ok_or(anyhow!("{} is not in a tag folder", path.display()))?;
+    let temp = path.with_extension(TEMP_EXTENSION);
+    let result = fs::write(&temp, data)
+        .map_err(Into::into)
+        .and_then(|_| replace_toniefile(content, &temp, path));
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    info!("wrote {}", path.display());
+    Ok(())
+}
+
+/// A new arrangement of the chapters of a Toniefile
+///
+/// Every new chapter is made of one or more of the chapters the Toniefile has now, chapters that
+/// are not used any more are removed. Only the header and the Ogg pages are rewritten, the audio
+/// is not encoded again.
+#[derive(Debug, Clone)]
+pub struct ChapterLayout {
+    pub path: PathBuf,
+    /// playing time of the chapters the Toniefile has now
+    pub durations: Vec<Duration>,
+    /// the new chapters as indices of the chapters the Toniefile has now
+    pub chapters: Vec<Vec<usize>>,
+}
+
+impl ChapterLayout {
+    /// The layout of a Toniefile as it is
+    pub fn load(path: &Path) -> Result<Self> {
+        let durations: Vec<Duration> = Taf::read(path)?
+            .chapters()
+            .iter()
+            .map(TafChapter::duration)
+            .collect();
+        Ok(Self {
+            path: path.to_path_buf(),
+            chapters: (0..durations.len()).map(|i| vec![i]).collect(),
+            durations,
+        })
+    }
+
+    pub fn duration(&self, index: usize) -> Duration {
+        self.chapters[index]
+            .iter()
+            .filter_map(|&i| self.durations.get(i))
+            .sum()
+    }
+
+    pub fn total_duration(&self) -> Duration {
+        (0..self.chapters.len()).map(|i| self.duration(i)).sum()
+    }
+
+    /// Merge the chapter at `index` with the one following it
+    pub fn merge_with_next(&mut self, index: usize) {
+        if index + 1 < self.chapters.len() {
+            let next = self.chapters.remove(index + 1);
+            self.chapters[index].extend(next);
+        }
+    }
+
+    /// Split a merged chapter at `index` into the chapters it was made of again
+    pub fn split(&mut self, index: usize) {
+        let parts = std::mem::take(&mut self.chapters[index]);
+        self.chapters
+            .splice(index..=index, parts.into_iter().map(|i| vec![i]));
+    }
+
+    pub fn is_unchanged(&self) -> bool {
+        self.chapters
+            .iter()
+            .enumerate()
+            .all(|(i, chapter)| chapter == &[i])
+            && self.chapters.len() == self.durations.len()
+    }
+
+    fn validate(&self, count: usize) -> Result<()> {
+        if self.chapters.is_empty() || self.chapters.iter().any(|c| c.is_empty()) {
+            return Err(anyhow!("a Toniefile needs at least one chapter"));
+        }
+        let mut used = vec![false; count];
+        for &i in self.chapters.iter().flatten() {
+            match used.get_mut(i) {
+                None => return Err(anyhow!("there is no chapter {}", i + 1)),
+                Some(true) => return Err(anyhow!("chapter {} is used more than once", i + 1)),
+                Some(u) => *u = true,
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrite the Toniefile with the new chapters, the old one goes to the trash
+    pub fn save(&self) -> Result<()> {
+        let taf = Taf::read(&self.path)?;
+        let old = taf.chapters();
+        self.validate(old.len())?;
+        let chapters = self
+            .chapters
+            .iter()
+            .map(|parts| TafChapter {
+                blocks: parts
+                    .iter()
+                    .flat_map(|&i| old[i].blocks.iter().cloned())
+                    .collect(),
+            })
+            .collect();
+        info!(
+            "rearranging the {} chapters of {} into {}",
+            old.len(),
+            self.path.display(),
+            self.chapters.len()
+        );
+        write_atomic(&self.path, &taf.rebuild(chapters)?)
+    }
+}
+
+/// Whether the file at `path` looks like a Toniefile
+pub fn is_toniefile(path: &Path) -> bool {
+    let mut buf = [0; 4];
+    let Ok(mut file) = File::open(path) else {
+        return false;
+    };
+    let header_len = file.read_exact(&mut buf).map(|_| buf);
+    let ogg = file
+        .seek(SeekFrom::Start(BLOCK_SIZE as u64))
+        .and_then(|_| file.read_exact(&mut buf))
+        .map(|_| buf);
+    header_len.is_ok_and(|b| u32::from_be_bytes(b) as usize == BLOCK_SIZE - 4)
+        && ogg.is_ok_and(|b| &b == b"OggS")
+}
+
+/// The chapters of a Toniefile as inputs for encoding it again
+pub fn toniefile_inputs(path: &Path) -> Result<Vec<ChapterInput>> {
+    let mut start = Duration::ZERO;
+    Ok(Taf::read(path)?
+        .chapters()
+        .iter()
+        .enumerate()
+        .map(|(i, chapter)| {
+            let end = start + chapter.duration();
+            let input = ChapterInput {
+                path: path.to_path_buf(),
+                start: Some(start),
+                end: Some(end),
+                title: Some(format!("Chapter {}", i + 1)),
+            };
+            start = end;
+            input
+        })
+        .collect())
+}
+
+/// Give the Toniefile at `path` another audio ID
+///
+/// The audio ID is also the serial number of the Ogg stream, so all pages are written again with
+/// the new one. The audio itself stays as it is.
+pub fn set_audio_id(path: &Path, audio_id: u32) -> Result<()> {
+    let taf = Taf::read(path)?;
+    info!(
+        "changing the audio ID of {} from {} to {}",
+        path.display(),
+        taf.header.audio_id,
+        audio_id
+    );
+    write_atomic(path, &taf.rebuild_with_id(taf.chapters(), audio_id)?)
+}
+
+/// Write the chapters in `range` of the Toniefile at `source` as a new Toniefile for `tag`
+///
+/// The new Toniefile gets a random audio ID that none of `tonies` has. With `remove` the
+/// chapters are taken out of `source` afterwards. Returns the path of the new Toniefile.
+pub fn split_chapters(
+    source: &Path,
+    range: Range<usize>,
+    content: &Path,
+    tag: &str,
+    remove: bool,
+    tonies: &Arc<Vec<Tonie>>,
+) -> Result<PathBuf> {
+    let dest = tag_path(content, tag)?;
+    if dest.exists() {
+        return Err(anyhow!("there already is a Toniefile for tag {}", tag));
+    }
+    let taf = Taf::read(source)?;
+    let mut chapters = taf.chapters();
+    if range.is_empty() || range.end > chapters.len() {
+        return Err(anyhow!(
+            "chapters {} to {} do not exist, the Toniefile has {}",
+            range.start + 1,
+            range.end,
+            chapters.len()
+        ));
+    }
+    if remove && range.len() == chapters.len() {
+        return Err(anyhow!("can not move all chapters out of a Toniefile"));
+    }
+    info!(
+        "writing chapters {} to {} of {} for tag {}",
+        range.start + 1,
+        range.end,
+        source.display(),
+        tag
+    );
+    let audio_id = AudioId::Random.resolve(tonies)?;
+    let data = taf.rebuild_with_id(chapters.drain(range.clone()).collect(), audio_id)?;
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_atomic(&dest, &data)?;
+    if remove {
+        let removed = ChapterLayout::load(source).and_then(|mut layout| {
+            layout.chapters.drain(range);
+            layout.save()
+        });
+        if let Err(e) = removed {
+            // otherwise the chapters would be in both Toniefiles
+            let _ = fs::remove_file(&dest);
+            if let Some(parent) = dest.parent() {
+                let _ = remove_dir_if_empty(parent);
+            }
+            return Err(e);
+        }
+    }
+    Ok(dest)
+}
+
+/// Returned by [`merge_files`] when the merged Toniefile was written, but not all sources could
+/// be moved to the trash
+#[derive(Debug)]
+pub struct SourcesLeft {
+    /// the sources that are in the trash now
+    pub removed: Vec<TrashEntry>,
+    pub error: anyhow::Error,
+}
+
+impl Display for SourcesLeft {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the Toniefiles were merged, but not all of them could be moved to the trash: {}",
+            self.error
+        )
+    }
+}
+
+impl std::error::Error for SourcesLeft {}
+
+/// Concatenate the chapters of the Toniefiles at `sources` into one Toniefile at `dest`
+///
+/// Sources that are given more than once are only merged once. `dest` can be one of the sources,
+/// then it keeps its audio ID, otherwise it gets a random audio ID that none of `tonies` has.
+/// With `remove` the other sources are moved to the trash of `content` afterwards and returned,
+/// otherwise they are left as they are. If one of them can not be moved, [`SourcesLeft`] is
+/// returned.
+pub fn merge_files(
+    sources: &[PathBuf],
+    dest: &Path,
+    content: &Path,
+    remove: bool,
+    tonies: &Arc<Vec<Tonie>>,
+) -> Result<Vec<TrashEntry>> {
+    let mut unique: Vec<&PathBuf> = vec![];
+    for source in sources {
+        if !unique.contains(&source) {
+            unique.push(source);
+        }
+    }
+    let sources = unique;
+    if sources.is_empty() {
+        return Err(anyhow!("no Toniefiles to merge"));
+    }
+    let base = sources.iter().position(|s| *s == dest);
+    if dest.exists() && base.is_none() {
+        return Err(anyhow!("{} already exists", dest.display()));
+    }
+    let tafs = sources
+        .iter()
+        .map(|path| Taf::read(path))
+        .collect::<Result<Vec<_>>>()?;
+    let chapters = tafs.iter().flat_map(Taf::chapters).collect();
+    info!(
+        "merging {} Toniefiles into {}",
+        sources.len(),
+        dest.display()
+    );
+    let data = match base {
+        Some(base) => tafs[base].rebuild(chapters)?,
+        // a second Toniefile with the audio ID of a source would be taken for a duplicate
+        None => tafs[0].rebuild_with_id(chapters, AudioId::Random.resolve(tonies)?)?,
+    };
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_atomic(dest, &data)?;
+    let mut removed = vec![];
+    if remove {
+        for source in sources.into_iter().filter(|s| *s != dest) {
+            match move_to_trash(content, source) {
+                Ok(entry) => removed.push(entry),
+                Err(error) => return Err(SourcesLeft { removed, error }.into()),
+            }
+            if let Some(parent) = source.parent() {
+                if let Err(e) = remove_dir_if_empty(parent) {
+                    error!("can not remove the folder {}: {}", parent.display(), e);
+                }
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Creates pages of silence to fill up blocks
+#[derive(Default)]
+struct SilenceSource {
+    packet: Option<Vec<u8>>,
+}
+
+impl SilenceSource {
+    /// A single Opus packet with 60 ms of silence
+    fn packet(&mut self) -> Result<&[u8]> {
+        if self.packet.is_none() {
+            let encoder = Encoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio)?;
+            let mut packet = vec![0; BLOCK_SIZE];
+            let len = encoder.encode(&[0; FRAME_SAMPLES * CHANNELS], &mut packet)?;
+            packet.truncate(len);
+            self.packet = Some(packet);
+        }
+        Ok(self.packet.as_deref().unwrap_or_default())
+    }
+
+    /// A page of exactly `len` bytes with silence
+    fn page(&mut self, len: usize) -> Result<OggPage> {
+        if len <= PAGE_HEADER_SIZE {
+            return Err(anyhow!(
+                "can not fill {} bytes with an Ogg page, its header alone takes {}",
+                len,
+                PAGE_HEADER_SIZE
+            ));
+        }
+        self.fill(&vec![], len, false)
+    }
+
+    /// The packets of the pages of `block` as one page of exactly `len` bytes
+    ///
+    /// The space that is left is filled with padded packets of 60 ms of silence, in front of the
+    /// packets with `in_front`. If it is too small for that, a packet of the block is padded.
+    fn fill(&mut self, block: &Block, len: usize, in_front: bool) -> Result<OggPage> {
+        let mut packets: Vec<Vec<u8>> = block
+            .iter()
+            .flat_map(OggPage::packets)
+            .map(<[u8]>::to_vec)
+            .collect();
+        let mut samples = block.iter().map(|p| p.samples).sum();
+        let used = PAGE_HEADER_SIZE + packets.iter().map(|p| laced_len(p.len())).sum::<usize>();
+        let gap = len.checked_sub(used).ok_or(anyhow!(
+            "{} bytes of Ogg pages do not fit in {}",
+            used,
+            len
+        ))?;
+        if gap > 0 {
+            let silence = match self.silence_packets(gap)? {
+                Some(silence) => silence,
+                None => {
+                    pad_any_packet(&mut packets, gap)?;
+                    vec![]
+                }
+            };
+            samples += silence.len() as u64 * FRAME_SAMPLES as u64;
+            if in_front {
+                packets.splice(0..0, silence);
+            } else {
+                packets.extend(silence);
+            }
+        }
+        let lacing: usize = packets.iter().map(|p| p.len() / 255 + 1).sum();
+        if lacing > 255 {
+            return Err(anyhow!("too many Opus packets for one Ogg page"));
+        }
+        let page = OggPage::with_packets(&packets, samples);
+        if page.len() != len {
+            return Err(anyhow!("can not fill {} bytes with an Ogg page", len));
+        }
+        Ok(page)
+    }
+
+    /// One or two padded packets of silence that take exactly `space` bytes in an Ogg page, None
+    /// if `space` is too small for them
+    fn silence_packets(&mut self, space: usize) -> Result<Option<Vec<Vec<u8>>>> {
+        let packet = self.packet()?;
+        // not every length can be reached by padding, because of the way it is encoded
+        let padded = |space| packet_len_for(space).and_then(|len| pad_packet(packet, len).ok());
+        if let Some(padded) = padded(space) {
+            return Ok(Some(vec![padded]));
+        }
+        // no single packet takes exactly 256 * n bytes, two packets do
+        for first in laced_len(packet.len())..space {
+            if let (Some(a), Some(b)) = (padded(first), padded(space - first)) {
+                return Ok(Some(vec![a, b]));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Make one of `packets` take `gap` more bytes in an Ogg page by padding it
+fn pad_any_packet(packets: &mut [Vec<u8>], gap: usize) -> Result<()> {
+    for packet in packets.iter_mut().rev() {
+        let space = laced_len(packet.len()) + gap;
+        if let Some(padded) = packet_len_for(space).and_then(|len| pad_packet(packet, len).ok()) {
+            *packet = padded;
+            return Ok(());
+        }
+    }
+    Err(anyhow!(
+        "can not fill the last {} bytes of a block, there is no Opus packet to pad",
+        gap
+    ))
+}
+
+/// Pad an Opus packet to `len` bytes (RFC 6716, section 3.2.5)
+pub(crate) fn pad_packet(packet: &[u8], len: usize) -> Result<Vec<u8>> {
+    let (&toc, rest) = packet.split_first().ok_or(anyhow!("empty Opus packet"))?;
+    // only packets with an arbitrary number of frames (code 3) can be padded, a packet with a
+    // single frame (code 0) is turned into one
+    let (count, frames) = match toc & 0x03 {
+        0 => (1, rest),
+        3 => {
+            let (&count, frames) = rest
+                .split_first()
+                .ok_or(anyhow!("Opus packet is truncated"))?;
+            if count & 0x40 != 0 {
+                return Err(anyhow!("Opus packet is already padded"));
+            }
+            (count, frames)
+        }
+        _ => return Err(anyhow!("can not pad an Opus packet with two frames")),
+    };
+    let unpadded = 2 + frames.len();
+    // every 255 in the padding length stands for 254 bytes of padding
+    let padding = (0..len.saturating_sub(unpadded))
+        .rev()
+        .find(|p| unpadded + p / 254 + 1 + p == len)
+        .ok_or(anyhow!("can not pad an Opus packet to {} bytes", len))?;
+    let mut padded = vec![toc | 0x03, count | 0x40];
+    padded.extend(std::iter::repeat_n(255, padding / 254));
+    padded.push((padding % 254) as u8);
+    padded.extend_from_slice(frames);
+    padded.resize(len, 0);
+    Ok(padded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::TafEncoder;
+    use crate::options::OpusSettings;
+
+    /// A Toniefile with a chapter of `seconds` of a tone for every entry
+    fn encode(audio_id: u32, chapters: &[usize]) -> Taf {
+        let mut encoder =
+            TafEncoder::new(Cursor::new(vec![]), audio_id, OpusSettings::default()).unwrap();
+        for (i, &seconds) in chapters.iter().enumerate() {
+            if i > 0 {
+                encoder.new_chapter().unwrap();
+            }
+            let pitch = 200.0 + 100.0 * i as f32;
+            let samples: Vec<i16> = (0..seconds * SAMPLE_RATE)
+                .flat_map(|n| {
+                    let t = n as f32 / SAMPLE_RATE as f32;
+                    let s = ((t * pitch * std::f32::consts::TAU).sin() * 8000.0) as i16;
+                    [s, s / 2]
+                })
+                .collect();
+            encoder.encode(&samples).unwrap();
+        }
+        let data = encoder.finalize().unwrap().into_inner();
+        Taf::from_bytes(&data).unwrap()
+    }
+
+    /// Every block after the first is a single Ogg page with a valid CRC, the audio page of the
+    /// first one ends at the block boundary
+    fn assert_one_page_per_block(data: &[u8]) {
+        let audio = &data[BLOCK_SIZE..];
+        assert_eq!(audio.len() % BLOCK_SIZE, 0);
+        let mut pos = 0;
+        for (block_no, block) in audio.chunks(BLOCK_SIZE).enumerate() {
+            if block_no == 0 {
+                // skip the Opus header pages
+                while OggPage::parse(&block[pos..]).unwrap().granule() == 0 {
+                    pos += OggPage::parse(&block[pos..]).unwrap().len();
+                }
+            } else {
+                pos = 0;
+            }
+            assert_eq!(&block[pos..pos + 4], b"OggS", "block {}", block_no);
+            let page = OggPage::parse(&block[pos..]).unwrap();
+            assert_eq!(pos + page.len(), BLOCK_SIZE, "block {}", block_no);
+            let mut zeroed = page.data().to_vec();
+            zeroed[22..26].fill(0);
+            assert_eq!(
+                ogg_crc(&zeroed).to_le_bytes(),
+                page.data()[22..26],
+                "block {}",
+                block_no
+            );
+        }
+    }
+
+    #[test]
+    fn append_keeps_one_page_per_block() {
+        let first = encode(1, &[3]);
+        let second = encode(2, &[2, 3]);
+        let mut chapters = first.chapters();
+        chapters.extend(second.chapters());
+        let data = first.rebuild(chapters).unwrap();
+        assert_one_page_per_block(&data);
+        let taf = Taf::from_bytes(&data).unwrap();
+        assert_eq!(taf.chapters().len(), 3);
+        assert_eq!(taf.header.audio_id, 1);
+    }
+
+    #[test]
+    fn reorder_keeps_one_page_per_block() {
+        let taf = encode(1, &[2, 2, 2]);
+        let mut chapters = taf.chapters();
+        chapters.rotate_left(1);
+        let data = taf.rebuild(chapters).unwrap();
+        assert_one_page_per_block(&data);
+        assert_eq!(Taf::from_bytes(&data).unwrap().chapters().len(), 3);
+    }
+
+    #[test]
+    fn fill_any_gap() {
+        let taf = encode(1, &[1]);
+        let mut block = taf.blocks[1].clone();
+        // make room in the block, then fill it up again
+        let page = &mut block[0];
+        let packets: Vec<Vec<u8>> = page.packets().iter().map(|p| p.to_vec()).collect();
+        *page = OggPage::with_packets(&packets[..packets.len() / 2], page.samples);
+        let len = block_len(&block);
+        let mut silence = SilenceSource::default();
+        for free in len..=len + 600 {
+            let page = silence.fill(&block, free, false).unwrap();
+            assert_eq!(page.len(), free);
+        }
+    }
+
+    #[test]
+    fn lacing() {
+        assert_eq!(laced_len(0), 1);
+        assert_eq!(laced_len(254), 255);
+        assert_eq!(laced_len(255), 257);
+        assert_eq!(laced_len(510), 513);
+    }
+
+    #[test]
+    fn packet_for_every_space_but_multiples_of_256() {
+        for space in 0..3 * BLOCK_SIZE {
+            match packet_len_for(space) {
+                Some(len) => assert_eq!(laced_len(len), space),
+                None => assert_eq!(space % 256, 0, "no packet for {} bytes", space),
+            }
+        }
+        for space in [256, 512, 768, BLOCK_SIZE] {
+            assert_eq!(packet_len_for(space), None);
+        }
+    }
+
+    #[test]
+    fn silence_page_too_small() {
+        let mut silence = SilenceSource::default();
+        assert!(silence.page(PAGE_HEADER_SIZE).is_err());
+        assert_eq!(silence.page(600).unwrap().len(), 600);
+    }
+}
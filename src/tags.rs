@@ -0,0 +1,87 @@
+use anyhow::Result;
+use std::fs::File;
+use std::path::Path;
+use std::time::Duration;
+use symphonia::core::codecs::CODEC_TYPE_NULL;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, StandardTagKey, Tag};
+use symphonia::core::probe::Hint;
+
+/// The metadata tags of an audio file RustyBench cares about
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AudioTags {
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub title: Option<String>,
+    /// length of the audio track, if the container tells it
+    pub duration: Option<Duration>,
+}
+
+// track and disc numbers are often stored as "3/12"
+fn parse_number(value: &str) -> Option<u32> {
+    value.split('/').next()?.trim().parse().ok()
+}
+
+impl AudioTags {
+    fn from_tags(tags: &[Tag]) -> Self {
+        let mut audio_tags = AudioTags::default();
+        for tag in tags {
+            let value = tag.value.to_string();
+            match tag.std_key {
+                Some(StandardTagKey::TrackNumber) => {
+                    audio_tags.track_number = audio_tags.track_number.or(parse_number(&value))
+                }
+                Some(StandardTagKey::DiscNumber) => {
+                    audio_tags.disc_number = audio_tags.disc_number.or(parse_number(&value))
+                }
+                Some(StandardTagKey::TrackTitle) if !value.trim().is_empty() => {
+                    audio_tags.title = audio_tags.title.or(Some(value.trim().to_string()))
+                }
+                _ => {}
+            }
+        }
+        audio_tags
+    }
+}
+
+/// Read the metadata tags and the duration of an audio file without decoding it
+pub fn read_tags(path: &Path) -> Result<AudioTags> {
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(ext);
+    }
+    let mss = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+    let mut probed = symphonia::default::get_probe().format(
+        &hint,
+        mss,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+
+    // tags can be found in front of the container (e.g. ID3v2) and in the container itself
+    let mut tags = vec![];
+    if let Some(metadata) = probed.metadata.get() {
+        if let Some(revision) = metadata.current() {
+            tags.extend_from_slice(revision.tags());
+        }
+    }
+    if let Some(revision) = probed.format.metadata().current() {
+        tags.extend_from_slice(revision.tags());
+    }
+    let mut audio_tags = AudioTags::from_tags(&tags);
+
+    audio_tags.duration = probed
+        .format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .and_then(|t| {
+            let time = t
+                .codec_params
+                .time_base?
+                .calc_time(t.codec_params.n_frames?);
+            Some(Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac))
+        });
+    Ok(audio_tags)
+}
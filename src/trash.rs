@@ -0,0 +1,229 @@
+use anyhow::{anyhow, Result};
+use log::info;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::index::sidecar_dir;
+use crate::{
+    change_tag_id, check_not_system, check_tag_id_validity, get_tag_id, move_file,
+    remove_dir_if_empty, swap_toniefiles, TagInUse,
+};
+
+const TRASH_DIR: &str = "trash";
+
+/// Folder of the trash of a CONTENT folder
+///
+/// Every deletion gets a folder named after the time in milliseconds, which holds the Toniefile
+/// with the same path it had in CONTENT.
+pub fn trash_dir(content: &Path) -> Result<PathBuf> {
+    Ok(sidecar_dir(content)?.join(TRASH_DIR))
+}
+
+/// A Toniefile in the trash
+#[derive(Debug, Clone)]
+pub struct TrashEntry {
+    /// where the Toniefile is in the trash
+    pub path: PathBuf,
+    /// where it was, relative to CONTENT
+    pub origin: PathBuf,
+    pub tag: String,
+    pub deleted: SystemTime,
+    pub size: u64,
+}
+
+impl TrashEntry {
+    fn from_path(trash: &Path, path: PathBuf) -> Result<Self> {
+        let not_in_trash = || anyhow!("{} is not in the trash", path.display());
+        let relative = path.strip_prefix(trash).map_err(|_| not_in_trash())?;
+        let mut components = relative.components();
+        let millis = components
+            .next()
+            .and_then(|c| c.as_os_str().to_str())
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(not_in_trash)?;
+        let origin = components.as_path().to_path_buf();
+        // files that were not in a tag folder have no tag ID
+        let tag = match origin.components().count() {
+            2 => get_tag_id(&origin).filter(|tag| check_tag_id_validity(tag).is_ok()),
+            _ => None,
+        };
+        Ok(Self {
+            tag: tag.unwrap_or("invalid".into()),
+            deleted: SystemTime::UNIX_EPOCH + Duration::from_millis(millis),
+            size: fs::metadata(&path)?.len(),
+            origin,
+            path,
+        })
+    }
+
+    /// How long ago the Toniefile was deleted, e.g. `5 minutes ago`
+    pub fn age(&self) -> String {
+        let secs = self.deleted.elapsed().unwrap_or_default().as_secs();
+        match secs {
+            0..=59 => "just now".to_string(),
+            60..=3599 => format!("{} minutes ago", secs / 60),
+            3600..=86399 => format!("{} hours ago", secs / 3600),
+            _ => format!("{} days ago", secs / 86400),
+        }
+    }
+}
+
+/// Move a Toniefile, or any other file below CONTENT, into the trash
+///
+/// Its folder in CONTENT is left alone, even if it is empty now.
+pub fn move_to_trash(content: &Path, path: &Path) -> Result<TrashEntry> {
+    check_not_system(path)?;
+    let origin = path
+        .strip_prefix(content)
+        .ok()
+        .filter(|origin| origin.components().count() > 0)
+        .ok_or(anyhow!("{} is not a file in CONTENT", path.display()))?;
+    let trash = trash_dir(content)?;
+    let mut millis = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_millis();
+    while trash.join(millis.to_string()).exists() {
+        millis += 1;
+    }
+    let dest = trash.join(millis.to_string()).join(origin);
+    let moved = dest
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .map_err(Into::into)
+        .and_then(|_| move_file(path, &dest));
+    if let Err(e) = moved {
+        let _ = remove_deletion_dirs(&trash, &dest);
+        return Err(e);
+    }
+    info!("moved {} to the trash", path.display());
+    TrashEntry::from_path(&trash, dest)
+}
+
+/// All files in the trash, the last deleted first
+pub fn list_trash(content: &Path) -> Result<Vec<TrashEntry>> {
+    let trash = trash_dir(content)?;
+    let mut entries = vec![];
+    if !trash.exists() {
+        return Ok(entries);
+    }
+    for deletion in fs::read_dir(&trash)? {
+        collect_entries(&trash, &deletion?.path(), &mut entries)?;
+    }
+    entries.sort_by_key(|e| std::cmp::Reverse(e.deleted));
+    Ok(entries)
+}
+
+fn collect_entries(trash: &Path, dir: &Path, entries: &mut Vec<TrashEntry>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_entries(trash, &path, entries)?;
+        } else {
+            entries.push(TrashEntry::from_path(trash, path)?);
+        }
+    }
+    Ok(())
+}
+
+/// Put a Toniefile from the trash back to where it was
+///
+/// A Toniefile that is there now is only replaced with `overwrite`, otherwise [`TagInUse`] is
+/// returned. The replaced Toniefile goes to the trash and is returned.
+pub fn restore(content: &Path, entry: &TrashEntry, overwrite: bool) -> Result<Option<TrashEntry>> {
+    let dest = content.join(&entry.origin);
+    let replaced = if dest.exists() {
+        if !overwrite {
+            return Err(TagInUse(entry.tag.clone()).into());
+        }
+        Some(move_to_trash(content, &dest)?)
+    } else {
+        None
+    };
+    let restored = dest
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .map_err(Into::into)
+        .and_then(|_| move_file(&entry.path, &dest));
+    if let Err(e) = restored {
+        if let Some(replaced) = &replaced {
+            let _ = restore(content, replaced, false);
+        }
+        return Err(e);
+    }
+    info!("restored {} from the trash", dest.display());
+    remove_deletion_dirs(&trash_dir(content)?, &entry.path)?;
+    Ok(replaced)
+}
+
+/// Delete all Toniefiles in the trash for good
+pub fn empty_trash(content: &Path) -> Result<()> {
+    let trash = trash_dir(content)?;
+    if trash.exists() {
+        fs::remove_dir_all(&trash)?;
+    }
+    info!("emptied the trash of {}", content.display());
+    Ok(())
+}
+
+/// Remove the folders that held a file in the trash, once they are empty
+fn remove_deletion_dirs(trash: &Path, path: &Path) -> Result<()> {
+    for dir in path.ancestors().skip(1).take_while(|dir| *dir != trash) {
+        if dir.exists() {
+            remove_dir_if_empty(dir)?;
+        }
+    }
+    Ok(())
+}
+
+/// An operation on the Toniefiles of a CONTENT folder that can be undone
+#[derive(Debug, Clone)]
+pub enum UndoStep {
+    /// a Toniefile was moved to the trash
+    Delete(TrashEntry),
+    /// the Toniefile now at `path` had the tag ID `tag` before, `replaced` is the Toniefile that
+    /// was in its new place
+    ChangeTagId {
+        path: PathBuf,
+        tag: String,
+        replaced: Option<TrashEntry>,
+    },
+    /// two Toniefiles traded places
+    Swap(PathBuf, PathBuf),
+}
+
+impl UndoStep {
+    pub fn undo(&self, content: &Path) -> Result<()> {
+        match self {
+            UndoStep::Delete(entry) => restore(content, entry, false).map(|_| ()),
+            UndoStep::ChangeTagId {
+                path,
+                tag,
+                replaced,
+            } => {
+                change_tag_id(content, path, tag, false)?;
+                match replaced {
+                    Some(entry) => restore(content, entry, false).map(|_| ()),
+                    None => Ok(()),
+                }
+            }
+            UndoStep::Swap(first, second) => swap_toniefiles(first, second),
+        }
+    }
+}
+
+impl Display for UndoStep {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UndoStep::Delete(entry) => write!(f, "deleting {}", entry.tag),
+            UndoStep::ChangeTagId { tag, .. } => write!(f, "changing tag ID {}", tag),
+            UndoStep::Swap(first, second) => write!(
+                f,
+                "swapping {} and {}",
+                get_tag_id(first).unwrap_or_default(),
+                get_tag_id(second).unwrap_or_default()
+            ),
+        }
+    }
+}
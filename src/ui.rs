@@ -1,3 +1,4 @@
+use anyhow::anyhow;
 use anyhow::Error;
 use anyhow::Result;
 use crossbeam::channel::{Receiver, Sender};
@@ -10,21 +11,67 @@ use egui::FontId;
 use egui::TextStyle::*;
 use egui_extras::{Column, TableBuilder};
 use log::{error, info};
+use std::time::Duration;
 use std::{ffi::OsStr, thread};
 use std::{path::PathBuf, sync::Arc};
 
+use crate::backup::{backup, restore_backup};
+use crate::capacity::{card_usage, CardUsage};
+use crate::chapters::{self, format_duration, plan_chapters, total_duration, Chapter};
+use crate::decode_report::{DecodeErrorPolicy, DecodeReport};
+use crate::diff::{diff_report, DiffReport};
+use crate::duplicates::{find_duplicates, DuplicateGroup};
+use crate::folder::collect_folder;
+use crate::health::{check_health, repair_all, Finding, Problem};
+use crate::jobs::{JobEvent, JobId, JobQueue, JobRequest, JobState, JobTarget};
+use crate::manifest::{plan_build, BuildAction, Manifest};
+use crate::options::{parse_audio_id, AudioId, EncodeOptions, BITRATES, COMPLEXITIES};
+use crate::playlist::{expand_inputs, ChapterInput};
+use crate::sync::{apply_all, compare, Difference, Direction};
+use crate::taf::{
+    merge_files, set_audio_id, split_chapters, toniefile_inputs, ChapterLayout, SourcesLeft,
+};
+use crate::tonielist::find_tonie_with_audio_id;
 use crate::tonielist::get_tonie_list_from_file;
 use crate::tonielist::get_tonie_list_online;
+use crate::trash::{empty_trash, list_trash, restore, TrashEntry, UndoStep};
 use crate::{
-    add_audio_file, change_tag_id, check_tag_id_validity, delete_file, extract_all, extract_to_ogg,
-    play_file, populate_table, tonielist::Tonie, Teddyfile,
+    change_tag_id, check_tag_id_validity, delete_file, extract_all, extract_to_ogg, format_size,
+    play_file, populate_system_files, populate_table, swap_toniefiles, tag_path, tonielist::Tonie,
+    TagInUse, Teddyfile,
 };
 
 #[derive(Debug, Clone)]
 pub enum Action {
     None,
     AskAddAudioFile,
+    AskAddFolder,
+    AskBuild,
+    AskAppendChapters,
+    AskReencode,
+    EditChapters,
+    SaveChapters,
+    SplitChapters,
+    MergeFiles,
+    SetAudioId,
+    SwapTags,
+    Undo,
+    ShowCapacity,
+    ShowTrash,
+    Backup,
+    RestoreBackup,
+    AskSync,
+    ApplySync,
+    AskCompare,
+    ShowDuplicates,
+    ShowHealth,
+    RepairHealth(Problem),
+    DeleteDuplicate(PathBuf),
+    RetagDuplicate(PathBuf),
+    RestoreFromTrash(usize),
+    EmptyTrash,
     AddAudioFile,
+    EncodeChapters,
     AskChangeTagId,
     ChangeTagId,
     PopulateTable,
@@ -33,29 +80,173 @@ pub enum Action {
     PlayFile,
     DeleteFile,
     ShowFileData,
-    Processing(u64),
-    CurrentFileNo(usize),
+    Job(JobId, JobEvent),
     CurrentFile(String),
 }
 
+/// Chapters of the selected Toniefile that are written for another tag
+pub struct SplitDialog {
+    pub path: PathBuf,
+    pub chapter_count: usize,
+    /// first and last chapter, counting from 1
+    pub first: usize,
+    pub last: usize,
+    pub tag: String,
+    /// take the chapters out of the selected Toniefile
+    pub remove: bool,
+}
+
+/// Toniefiles whose chapters are added to the selected one
+pub struct MergeDialog {
+    pub dest: PathBuf,
+    /// all other Toniefiles with their tag and whether they are merged
+    pub sources: Vec<(PathBuf, String, bool)>,
+    /// move the merged Toniefiles to the trash
+    pub remove: bool,
+}
+
+/// Another Toniefile whose tag is exchanged with the one of the selected Toniefile
+pub struct SwapDialog {
+    pub path: PathBuf,
+    pub tag: String,
+    /// all other Toniefiles with their tag
+    pub others: Vec<(PathBuf, String)>,
+    pub other: usize,
+}
+
+/// Differences between the CONTENT folder and another one, and how they are synced
+pub struct SyncDialog {
+    pub other: PathBuf,
+    /// every difference with whether it is synced and which way
+    pub differences: Vec<(Difference, bool, Direction)>,
+}
+
+/// Where the audio ID of a Toniefile comes from, as chosen in a dialog
+#[derive(Default)]
+pub struct AudioIdChoice {
+    pub audio_id: AudioId,
+    /// text of the field for a fixed audio ID
+    pub text: String,
+}
+
+impl AudioIdChoice {
+    /// Show the choices, returns false while the entered audio ID is invalid
+    fn show(&mut self, ui: &mut egui::Ui, tonies: &Arc<Vec<Tonie>>) -> bool {
+        ui.label("audio ID: ");
+        if ui
+            .radio(self.audio_id == AudioId::Random, "random")
+            .clicked()
+        {
+            self.audio_id = AudioId::Random;
+        }
+        if ui
+            .radio(
+                self.audio_id == AudioId::Now,
+                "current time, like official Toniefiles",
+            )
+            .clicked()
+        {
+            self.audio_id = AudioId::Now;
+        }
+        ui.horizontal(|ui| {
+            let selected = matches!(self.audio_id, AudioId::Fixed(_));
+            let clicked = ui.radio(selected, "this one:").clicked();
+            let edited = ui
+                .add(
+                    egui::TextEdit::singleline(&mut self.text)
+                        .desired_width(100.0)
+                        .font(FontId::default()),
+                )
+                .changed();
+            if selected || clicked || edited {
+                self.audio_id = AudioId::Fixed(parse_audio_id(&self.text).unwrap_or_default());
+            }
+        });
+        ui.horizontal(|ui| {
+            let selected = matches!(self.audio_id, AudioId::CopyFrom(_));
+            if ui
+                .radio(selected, "the one of another Toniefile...")
+                .clicked()
+            {
+                if let Some(path) = rfd::FileDialog::new().pick_file() {
+                    self.audio_id = AudioId::CopyFrom(path);
+                }
+            }
+            if let AudioId::CopyFrom(path) = &self.audio_id {
+                ui.label(path.to_string_lossy());
+            }
+        });
+        let AudioId::Fixed(id) = self.audio_id else {
+            return true;
+        };
+        if parse_audio_id(&self.text).is_err() {
+            ui.label("invalid audio ID");
+            return false;
+        }
+        // the Toniebox would take the Toniefile for this tonie
+        if let Some(tonie) = find_tonie_with_audio_id(tonies, id) {
+            ui.label(
+                RichText::new(format!("This is the audio ID of tonie {}", tonie.article))
+                    .color(Color32::YELLOW),
+            );
+        }
+        true
+    }
+}
+
+/// New audio ID for the selected Toniefile
+pub struct AudioIdDialog {
+    pub path: PathBuf,
+    pub current: u32,
+    pub choice: AudioIdChoice,
+}
+
 pub struct RustyBench {
     pub picked_path: PathBuf,
     pub picked_file: PathBuf,
-    pub picked_files: Vec<PathBuf>,
+    pub picked_chapters: Vec<Chapter>,
+    /// where the chapters in the chapter editor are encoded to, a new Toniefile if None
+    pub chapter_target: Option<JobTarget>,
+    /// chapters of an existing Toniefile that are being rearranged
+    pub chapter_layout: Option<ChapterLayout>,
+    pub split_dialog: Option<SplitDialog>,
+    pub merge_dialog: Option<MergeDialog>,
+    pub audio_id_dialog: Option<AudioIdDialog>,
+    pub swap_dialog: Option<SwapDialog>,
+    pub sync_dialog: Option<SyncDialog>,
+    pub diff_report: Option<DiffReport>,
+    /// Toniefiles with the same audio or audio ID while the duplicates window is open
+    pub duplicates: Option<Vec<DuplicateGroup>>,
+    /// findings of the health check while its window is open
+    pub health: Option<Vec<Finding>>,
+    /// audio ID of the next Toniefile added with the tag ID popup
+    pub audio_id_choice: AudioIdChoice,
+    pub card_usage: Option<CardUsage>,
+    /// Toniefiles in the trash while the trash window is open
+    pub trash: Option<Vec<TrashEntry>>,
+    /// deletions, tag changes and swaps in the current CONTENT folder, the last one at the end
+    pub undo_history: Vec<UndoStep>,
     pub files: Vec<Teddyfile>,
+    /// list the files of the Toniebox itself in the `000000*` folders, too
+    pub show_system_files: bool,
     pub selection: Option<usize>,
     pub show_id_popup: bool,
+    pub show_chapter_editor: bool,
+    /// free space on the card when the chapter editor was opened, checked once and not on every
+    /// frame
+    pub free_space: Option<u64>,
     pub tag_id: String,
     pub tag_id_valid: bool,
     pub error: Option<Error>,
     pub action: Action,
     pub thread_receiver: Receiver<Action>,
     pub thread_sender: Sender<Action>,
-    pub processed: u64,
-    pub current_fileno: usize,
     pub current_file: String,
     pub joinhandles: Vec<thread::JoinHandle<Result<(), Error>>>,
     pub tonies: Arc<Vec<Tonie>>,
+    pub encode_options: EncodeOptions,
+    pub decode_report: Option<DecodeReport>,
+    pub jobs: JobQueue,
 }
 
 impl Default for RustyBench {
@@ -69,21 +260,39 @@ impl Default for RustyBench {
         Self {
             picked_path: Default::default(),
             picked_file: Default::default(),
-            picked_files: vec![],
+            picked_chapters: vec![],
+            chapter_target: None,
+            chapter_layout: None,
+            split_dialog: None,
+            merge_dialog: None,
+            audio_id_dialog: None,
+            swap_dialog: None,
+            sync_dialog: None,
+            diff_report: None,
+            duplicates: None,
+            health: None,
+            audio_id_choice: Default::default(),
+            card_usage: None,
+            trash: None,
+            undo_history: vec![],
             files: vec![],
+            show_system_files: false,
             selection: None,
             show_id_popup: false,
+            show_chapter_editor: false,
+            free_space: None,
             tag_id: "E0040350".to_string(),
             tag_id_valid: false,
             error: None,
             action: Action::None,
             thread_receiver,
             thread_sender,
-            processed: 0,
-            current_fileno: 0,
             current_file: "".to_string(),
             joinhandles: vec![],
             tonies,
+            encode_options: Default::default(),
+            decode_report: None,
+            jobs: Default::default(),
         }
     }
 }
@@ -109,6 +318,50 @@ impl RustyBench {
         .into();
         ctx.set_style(style);
     }
+    fn dialog_open(&self) -> bool {
+        self.show_id_popup
+            || self.show_chapter_editor
+            || self.chapter_layout.is_some()
+            || self.split_dialog.is_some()
+            || self.merge_dialog.is_some()
+            || self.audio_id_dialog.is_some()
+            || self.swap_dialog.is_some()
+            || self.sync_dialog.is_some()
+            || self.diff_report.is_some()
+            || self.card_usage.is_some()
+            || self.trash.is_some()
+            || self.health.is_some()
+    }
+    fn show_inputs(&mut self, inputs: Result<Vec<ChapterInput>>) {
+        match inputs {
+            Ok(inputs) => {
+                self.picked_chapters = plan_chapters(inputs);
+                self.free_space = fs2::available_space(&self.picked_path).ok();
+                self.show_chapter_editor = true;
+            }
+            Err(e) => {
+                self.chapter_target = None;
+                self.error = Some(e);
+            }
+        }
+    }
+    /// Whether the selected file may be changed, system files can only be played and extracted
+    fn selection_editable(&self) -> bool {
+        self.selection
+            .and_then(|i| self.files.get(i))
+            .is_some_and(|file| !file.is_system())
+    }
+    /// List the files of the CONTENT folder again
+    fn reload_files(&mut self) {
+        self.selection = None;
+        self.files.clear();
+        populate_table(&self.picked_path, &mut self.files, &self.tonies)
+            .unwrap_or_else(|e| self.error = Some(e));
+        if self.show_system_files {
+            populate_system_files(&self.picked_path, &mut self.files, &self.tonies)
+                .unwrap_or_else(|e| self.error = Some(e));
+        }
+    }
     fn toggle_row_selection(&mut self, row_index: usize, row_response: &egui::Response) {
         if row_response.clicked() {
             self.selection = Some(row_index);
@@ -149,7 +402,7 @@ impl RustyBench {
 impl eframe::App for RustyBench {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
-            ui.set_enabled(!self.show_id_popup);
+            ui.set_enabled(!self.dialog_open());
             egui::menu::bar(ui, |ui| {
                 ui.menu_button("File", |ui| {
                     if ui.button("Quit").clicked() {
@@ -158,9 +411,53 @@ impl eframe::App for RustyBench {
                     if ui.button("Choose CONTENT folder...").clicked() {
                         if let Some(path) = rfd::FileDialog::new().pick_folder() {
                             self.picked_path = path;
+                            self.undo_history.clear();
                             self.action = Action::PopulateTable;
                         }
                     }
+                    if ui
+                        .checkbox(&mut self.show_system_files, "Show system files")
+                        .on_hover_text("The files of the Toniebox itself, they can only be played and extracted")
+                        .changed()
+                    {
+                        self.action = Action::PopulateTable;
+                    }
+                    if ui.button("Add folder...").clicked() && self.picked_path.exists() {
+                        self.action = Action::AskAddFolder;
+                    }
+                    if ui.button("Build from manifest...").clicked() && self.picked_path.exists() {
+                        self.action = Action::AskBuild;
+                    }
+                    if ui.button("Card capacity...").clicked() && self.picked_path.exists() {
+                        self.action = Action::ShowCapacity;
+                    }
+                    if ui.button("Find duplicates...").clicked() && self.picked_path.exists() {
+                        self.action = Action::ShowDuplicates;
+                    }
+                    if ui.button("Health check...").clicked() && self.picked_path.exists() {
+                        self.action = Action::ShowHealth;
+                    }
+                    if ui.button("Trash...").clicked() && self.picked_path.exists() {
+                        self.action = Action::ShowTrash;
+                    }
+                    if ui
+                        .button("Compare with another CONTENT folder...")
+                        .clicked()
+                        && self.picked_path.exists()
+                    {
+                        self.action = Action::AskCompare;
+                    }
+                    if ui.button("Sync with another CONTENT folder...").clicked()
+                        && self.picked_path.exists()
+                    {
+                        self.action = Action::AskSync;
+                    }
+                    if ui.button("Back up card...").clicked() && self.picked_path.exists() {
+                        self.action = Action::Backup;
+                    }
+                    if ui.button("Restore backup to a fresh card...").clicked() {
+                        self.action = Action::RestoreBackup;
+                    }
                     if ui.button("Load toniesV2.json file").clicked() {
                         if let Some(path) = rfd::FileDialog::new().pick_file() {
                             let tonielist = match get_tonie_list_from_file(path) {
@@ -175,11 +472,196 @@ impl eframe::App for RustyBench {
                         }
                     }
                 });
+                ui.menu_button("Edit", |ui| {
+                    let undo = match self.undo_history.last() {
+                        Some(step) => format!("Undo {}", step),
+                        None => "Undo".to_string(),
+                    };
+                    if ui
+                        .add_enabled(!self.undo_history.is_empty(), egui::Button::new(undo))
+                        .clicked()
+                    {
+                        ui.close_menu();
+                        self.action = Action::Undo;
+                    }
+                    ui.separator();
+                    if ui
+                        .add_enabled(
+                            self.selection_editable(),
+                            egui::Button::new("Append chapters..."),
+                        )
+                        .clicked()
+                    {
+                        ui.close_menu();
+                        self.action = Action::AskAppendChapters;
+                    }
+                    if ui
+                        .add_enabled(self.selection_editable(), egui::Button::new("Re-encode..."))
+                        .clicked()
+                    {
+                        ui.close_menu();
+                        self.action = Action::AskReencode;
+                    }
+                    if ui
+                        .add_enabled(
+                            self.selection_editable(),
+                            egui::Button::new("Edit chapters..."),
+                        )
+                        .clicked()
+                    {
+                        ui.close_menu();
+                        self.action = Action::EditChapters;
+                    }
+                    if ui
+                        .add_enabled(
+                            self.selection_editable(),
+                            egui::Button::new("Move chapters to another tag..."),
+                        )
+                        .clicked()
+                    {
+                        ui.close_menu();
+                        let file = &self.files[self.selection.unwrap()];
+                        let chapter_count = file.chapter_pages.len().max(1);
+                        self.split_dialog = Some(SplitDialog {
+                            path: file.path.clone(),
+                            chapter_count,
+                            first: 1,
+                            last: chapter_count,
+                            tag: "E0040350".to_string(),
+                            remove: true,
+                        });
+                    }
+                    if ui
+                        .add_enabled(
+                            self.selection_editable(),
+                            egui::Button::new("Merge other Toniefiles into this one..."),
+                        )
+                        .clicked()
+                    {
+                        ui.close_menu();
+                        let dest = self.files[self.selection.unwrap()].path.clone();
+                        self.merge_dialog = Some(MergeDialog {
+                            sources: self
+                                .files
+                                .iter()
+                                .filter(|f| f.path != dest && !f.is_system())
+                                .map(|f| (f.path.clone(), f.tag.clone(), false))
+                                .collect(),
+                            dest,
+                            remove: false,
+                        });
+                    }
+                    // system files can not be swapped, so there may be nothing to swap with
+                    let others: Vec<_> = if self.selection_editable() {
+                        let path = &self.files[self.selection.unwrap()].path;
+                        self.files
+                            .iter()
+                            .filter(|f| &f.path != path && !f.is_system())
+                            .map(|f| (f.path.clone(), f.tag.clone()))
+                            .collect()
+                    } else {
+                        vec![]
+                    };
+                    if ui
+                        .add_enabled(!others.is_empty(), egui::Button::new("Swap tag with..."))
+                        .clicked()
+                    {
+                        ui.close_menu();
+                        let file = &self.files[self.selection.unwrap()];
+                        self.swap_dialog = Some(SwapDialog {
+                            others,
+                            other: 0,
+                            path: file.path.clone(),
+                            tag: file.tag.clone(),
+                        });
+                    }
+                    if ui
+                        .add_enabled(
+                            self.selection_editable(),
+                            egui::Button::new("Change audio ID..."),
+                        )
+                        .clicked()
+                    {
+                        ui.close_menu();
+                        let file = &self.files[self.selection.unwrap()];
+                        self.audio_id_dialog = Some(AudioIdDialog {
+                            path: file.path.clone(),
+                            current: file.audio_id,
+                            choice: AudioIdChoice {
+                                audio_id: AudioId::Now,
+                                text: file.audio_id.to_string(),
+                            },
+                        });
+                    }
+                });
+                ui.menu_button("Settings", |ui| {
+                    ui.label("On broken packets in input files:");
+                    ui.radio_value(
+                        &mut self.encode_options.policy,
+                        DecodeErrorPolicy::Skip,
+                        "skip them",
+                    );
+                    ui.radio_value(
+                        &mut self.encode_options.policy,
+                        DecodeErrorPolicy::Fail,
+                        "abort the job",
+                    );
+                    let threshold = match self.encode_options.policy {
+                        DecodeErrorPolicy::FailAbove(n) => n,
+                        _ => 10,
+                    };
+                    ui.horizontal(|ui| {
+                        ui.radio_value(
+                            &mut self.encode_options.policy,
+                            DecodeErrorPolicy::FailAbove(threshold),
+                            "abort the job above",
+                        );
+                        if let DecodeErrorPolicy::FailAbove(n) = &mut self.encode_options.policy {
+                            ui.add(egui::DragValue::new(n).clamp_range(0..=10000));
+                        }
+                    });
+                    ui.separator();
+                    ui.checkbox(
+                        &mut self.encode_options.normalize,
+                        "Normalize the volume of every file",
+                    );
+                    ui.checkbox(
+                        &mut self.encode_options.trim_silence,
+                        "Cut silence at the start and end of every file",
+                    );
+                    ui.horizontal(|ui| {
+                        ui.label("Silence between chapters:");
+                        let mut gap = self.encode_options.gap.as_secs_f64();
+                        let drag = egui::DragValue::new(&mut gap)
+                            .clamp_range(0.0..=60.0)
+                            .speed(0.1)
+                            .suffix(" s");
+                        if ui.add(drag).changed() {
+                            self.encode_options.gap = Duration::from_secs_f64(gap);
+                        }
+                    });
+                    ui.separator();
+                    ui.label("Opus encoder (official Toniefiles have 96 kbit/s):");
+                    ui.horizontal(|ui| {
+                        ui.label("Bitrate:");
+                        ui.add(
+                            egui::Slider::new(&mut self.encode_options.opus.bitrate, BITRATES)
+                                .suffix(" kbit/s"),
+                        );
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Complexity:");
+                        ui.add(egui::Slider::new(
+                            &mut self.encode_options.opus.complexity,
+                            COMPLEXITIES,
+                        ));
+                    });
+                });
             });
         });
 
         egui::CentralPanel::default().show(ctx, |ui| {
-            ui.set_enabled(!self.show_id_popup);
+            ui.set_enabled(!self.dialog_open());
             ui.horizontal(|ui| {
                 ui.label("Folder: ");
                 ui.add(
@@ -231,12 +713,11 @@ impl eframe::App for RustyBench {
                             });
                             // column Tag ID
                             row.col(|ui| {
-                                ui.label(
-                                    egui::RichText::new(
-                                        self.format_tag_id(&self.files[row_index].tag),
-                                    )
-                                    .monospace(),
-                                );
+                                let tag = match self.files[row_index].is_system() {
+                                    true => "system file".to_string(),
+                                    false => self.format_tag_id(&self.files[row_index].tag),
+                                };
+                                ui.label(egui::RichText::new(tag).monospace());
                             });
                             // column Info
                             row.col(|ui| {
@@ -247,7 +728,7 @@ impl eframe::App for RustyBench {
                                         &t.data[0].episode.clone().unwrap_or_default()
                                     ));
                                 } else {
-                                    ui.label("unknown");
+                                    ui.label(self.files[row_index].describe());
                                 }
                             });
                             self.toggle_row_selection(row_index, &row.response());
@@ -257,7 +738,7 @@ impl eframe::App for RustyBench {
         });
 
         egui::TopBottomPanel::bottom("Bottom Panel").show(ctx, |ui| {
-            ui.set_enabled(!self.show_id_popup);
+            ui.set_enabled(!self.dialog_open());
             ui.horizontal_centered(|ui| {
                 if ui
                     .add_sized(
@@ -268,6 +749,7 @@ impl eframe::App for RustyBench {
                 {
                     if let Some(path) = rfd::FileDialog::new().pick_folder() {
                         self.picked_path = path;
+                        self.undo_history.clear();
                     }
                     self.action = Action::PopulateTable;
                 }
@@ -288,7 +770,7 @@ impl eframe::App for RustyBench {
                             .fill(Color32::RED),
                     )
                     .clicked()
-                    && self.selection.is_some()
+                    && self.selection_editable()
                 {
                     self.action = Action::DeleteFile;
                 }
@@ -328,7 +810,7 @@ impl eframe::App for RustyBench {
                         egui::Button::new("Change tag ID").fill(Color32::BLUE),
                     )
                     .clicked()
-                    && self.selection.is_some()
+                    && self.selection_editable()
                 {
                     self.action = Action::AskChangeTagId;
                 }
@@ -337,20 +819,72 @@ impl eframe::App for RustyBench {
 
         egui::TopBottomPanel::bottom("Messages Panel").show(ctx, |ui| {
             ui.horizontal(|ui| {
-                if self.processed > 0 {
-                    ui.label(format!(
-                        "File {} / {} ",
-                        self.current_fileno,
-                        self.picked_files.len()
-                    ));
-                    ui.label(format!("processed: {}%", self.processed));
-                }
                 if !self.current_file.is_empty() {
-                    ui.label(format!("Extracting file: {}", self.current_file));
+                    ui.label(&self.current_file);
                 }
             });
         });
 
+        if !self.jobs.jobs().is_empty() {
+            egui::TopBottomPanel::bottom("Jobs Panel").show(ctx, |ui| {
+                let mut cancel = None;
+                let mut show_report = None;
+                ui.horizontal(|ui| {
+                    ui.label("Jobs");
+                    if ui.button("Clear finished").clicked() {
+                        self.jobs.clear_finished();
+                    }
+                });
+                egui::ScrollArea::vertical()
+                    .max_height(120.0)
+                    .show(ui, |ui| {
+                        egui::Grid::new("jobs").striped(true).show(ui, |ui| {
+                            for job in self.jobs.jobs() {
+                                ui.label(format!("#{}", job.id));
+                                ui.label(&job.description);
+                                match &job.state {
+                                    JobState::Running => {
+                                        ui.add(
+                                            egui::ProgressBar::new(
+                                                job.progress.total_percent as f32 / 100.0,
+                                            )
+                                            .desired_width(200.0)
+                                            .text(
+                                                format!(
+                                                    "File {} / {}, ETA {}",
+                                                    job.current_fileno,
+                                                    job.file_count,
+                                                    job.progress.eta_string()
+                                                ),
+                                            ),
+                                        );
+                                    }
+                                    state => {
+                                        ui.label(state.to_string());
+                                    }
+                                }
+                                if job.state.is_finished() {
+                                    if job.report.is_some()
+                                        && ui.button("Skipped packets").clicked()
+                                    {
+                                        show_report = job.report.clone();
+                                    }
+                                } else if ui.button("Cancel").clicked() {
+                                    cancel = Some(job.id);
+                                }
+                                ui.end_row();
+                            }
+                        });
+                    });
+                if let Some(id) = cancel {
+                    self.jobs.cancel(id);
+                }
+                if show_report.is_some() {
+                    self.decode_report = show_report;
+                }
+            });
+        }
+
         if self.show_id_popup {
             egui::Window::new("Please provide a tag ID")
                 .collapsible(false)
@@ -364,7 +898,15 @@ impl eframe::App for RustyBench {
                             .font(FontId::default());
                         let _output = text_edit.show(ui);
                     }
-                    ui.horizontal(|ui| match check_tag_id_validity(&self.tag_id) {
+                    // only new Toniefiles get an audio ID, a changed tag ID keeps it
+                    let audio_id_valid = !matches!(self.action, Action::AddAudioFile)
+                        || self.audio_id_choice.show(ui, &self.tonies);
+                    let valid = check_tag_id_validity(&self.tag_id).and_then(|_| {
+                        audio_id_valid
+                            .then_some(())
+                            .ok_or(anyhow!("choose a valid audio ID"))
+                    });
+                    ui.horizontal(|ui| match valid {
                         Ok(_) => {
                             ui.label("Tag ID is valid");
                             ui.with_layout(
@@ -406,6 +948,742 @@ impl eframe::App for RustyBench {
                 });
         }
 
+        if self.show_chapter_editor {
+            let title = match self.chapter_target {
+                Some(JobTarget::Append(_)) => "Chapters to append",
+                Some(JobTarget::Tag { .. }) => "Chapters of the re-encoded Toniefile",
+                None => "Chapters of the new Toniefile",
+            };
+            egui::Window::new(title)
+                .collapsible(false)
+                .resizable(true)
+                .show(ctx, |ui| {
+                    let mut move_up = None;
+                    let mut move_down = None;
+                    let mut merge = None;
+                    let mut split = None;
+                    let mut remove = None;
+                    egui::ScrollArea::vertical()
+                        .max_height(350.0)
+                        .show(ui, |ui| {
+                            egui::Grid::new("chapter_editor")
+                                .striped(true)
+                                .show(ui, |ui| {
+                                    ui.label("#");
+                                    ui.label("Title");
+                                    ui.label("Duration");
+                                    ui.label("Files");
+                                    ui.end_row();
+                                    let count = self.picked_chapters.len();
+                                    for (i, chapter) in self.picked_chapters.iter_mut().enumerate()
+                                    {
+                                        ui.label(format!("{}", i + 1));
+                                        ui.text_edit_singleline(&mut chapter.title);
+                                        ui.label(
+                                            RichText::new(format_duration(chapter.duration))
+                                                .monospace(),
+                                        );
+                                        ui.vertical(|ui| {
+                                            for input in &chapter.inputs {
+                                                ui.label(
+                                                    RichText::new(
+                                                        input
+                                                            .path
+                                                            .file_name()
+                                                            .unwrap_or_default()
+                                                            .to_string_lossy(),
+                                                    )
+                                                    .monospace(),
+                                                );
+                                            }
+                                        });
+                                        if ui.add_enabled(i > 0, egui::Button::new("⬆")).clicked()
+                                        {
+                                            move_up = Some(i);
+                                        }
+                                        if ui
+                                            .add_enabled(i + 1 < count, egui::Button::new("⬇"))
+                                            .clicked()
+                                        {
+                                            move_down = Some(i);
+                                        }
+                                        if ui
+                                            .add_enabled(
+                                                i + 1 < count,
+                                                egui::Button::new("Merge with next"),
+                                            )
+                                            .clicked()
+                                        {
+                                            merge = Some(i);
+                                        }
+                                        if ui
+                                            .add_enabled(
+                                                chapter.inputs.len() > 1,
+                                                egui::Button::new("Split"),
+                                            )
+                                            .clicked()
+                                        {
+                                            split = Some(i);
+                                        }
+                                        if ui.button("✖").clicked() {
+                                            remove = Some(i);
+                                        }
+                                        ui.end_row();
+                                    }
+                                });
+                        });
+                    if let Some(i) = move_up {
+                        self.picked_chapters.swap(i, i - 1);
+                    }
+                    if let Some(i) = move_down {
+                        self.picked_chapters.swap(i, i + 1);
+                    }
+                    if let Some(i) = merge {
+                        chapters::merge_with_next(&mut self.picked_chapters, i);
+                    }
+                    if let Some(i) = split {
+                        chapters::split(&mut self.picked_chapters, i);
+                    }
+                    if let Some(i) = remove {
+                        self.picked_chapters.remove(i);
+                    }
+                    let duration = total_duration(&self.picked_chapters);
+                    ui.label(format!(
+                        "{} chapters, total duration {}",
+                        self.picked_chapters.len(),
+                        format_duration(duration)
+                    ));
+                    if let Some(duration) = duration {
+                        let size = self
+                            .encode_options
+                            .opus
+                            .estimated_size(duration, self.picked_chapters.len());
+                        let text = format!(
+                            "estimated size at {} kbit/s: {}",
+                            self.encode_options.opus.bitrate,
+                            format_size(size)
+                        );
+                        match self.free_space {
+                            Some(available) if available < size => {
+                                ui.label(RichText::new(text).color(Color32::RED));
+                                ui.label(format!(
+                                    "only {} are free on the card",
+                                    format_size(available)
+                                ));
+                            }
+                            Some(available) => {
+                                ui.label(text);
+                                ui.label(format!("{} free on the card", format_size(available)));
+                            }
+                            None => {
+                                ui.label(text);
+                            }
+                        }
+                    }
+                    ui.horizontal(|ui| {
+                        if ui.button("Cancel").clicked() {
+                            self.show_chapter_editor = false;
+                            self.picked_chapters.clear();
+                            self.chapter_target = None;
+                        }
+                        if ui
+                            .add_enabled(!self.picked_chapters.is_empty(), egui::Button::new("Ok"))
+                            .clicked()
+                        {
+                            self.show_chapter_editor = false;
+                            if self.chapter_target.is_some() {
+                                self.action = Action::EncodeChapters;
+                            } else {
+                                self.show_id_popup = true;
+                                self.action = Action::AddAudioFile;
+                            }
+                        }
+                    });
+                });
+        }
+
+        let mut close_layout = false;
+        if let Some(layout) = &mut self.chapter_layout {
+            egui::Window::new(format!(
+                "Chapters of {}",
+                layout
+                    .path
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+            ))
+            .collapsible(false)
+            .resizable(true)
+            .show(ctx, |ui| {
+                let mut move_up = None;
+                let mut move_down = None;
+                let mut merge = None;
+                let mut split = None;
+                let mut remove = None;
+                egui::ScrollArea::vertical()
+                    .max_height(350.0)
+                    .show(ui, |ui| {
+                        egui::Grid::new("chapter_layout")
+                            .striped(true)
+                            .show(ui, |ui| {
+                                ui.label("#");
+                                ui.label("Chapters now");
+                                ui.label("Duration");
+                                ui.end_row();
+                                let count = layout.chapters.len();
+                                for i in 0..count {
+                                    ui.label(format!("{}", i + 1));
+                                    let parts: Vec<String> = layout.chapters[i]
+                                        .iter()
+                                        .map(|part| (part + 1).to_string())
+                                        .collect();
+                                    ui.label(RichText::new(parts.join(" + ")).monospace());
+                                    ui.label(
+                                        RichText::new(format_duration(Some(layout.duration(i))))
+                                            .monospace(),
+                                    );
+                                    if ui.add_enabled(i > 0, egui::Button::new("⬆")).clicked() {
+                                        move_up = Some(i);
+                                    }
+                                    if ui
+                                        .add_enabled(i + 1 < count, egui::Button::new("⬇"))
+                                        .clicked()
+                                    {
+                                        move_down = Some(i);
+                                    }
+                                    if ui
+                                        .add_enabled(
+                                            i + 1 < count,
+                                            egui::Button::new("Merge with next"),
+                                        )
+                                        .clicked()
+                                    {
+                                        merge = Some(i);
+                                    }
+                                    if ui
+                                        .add_enabled(
+                                            layout.chapters[i].len() > 1,
+                                            egui::Button::new("Split"),
+                                        )
+                                        .clicked()
+                                    {
+                                        split = Some(i);
+                                    }
+                                    if ui.add_enabled(count > 1, egui::Button::new("✖")).clicked()
+                                    {
+                                        remove = Some(i);
+                                    }
+                                    ui.end_row();
+                                }
+                            });
+                    });
+                if let Some(i) = move_up {
+                    layout.chapters.swap(i, i - 1);
+                }
+                if let Some(i) = move_down {
+                    layout.chapters.swap(i, i + 1);
+                }
+                if let Some(i) = merge {
+                    layout.merge_with_next(i);
+                }
+                if let Some(i) = split {
+                    layout.split(i);
+                }
+                if let Some(i) = remove {
+                    layout.chapters.remove(i);
+                }
+                ui.label(format!(
+                    "{} chapters, total duration {}",
+                    layout.chapters.len(),
+                    format_duration(Some(layout.total_duration()))
+                ));
+                ui.horizontal(|ui| {
+                    if ui.button("Cancel").clicked() {
+                        close_layout = true;
+                    }
+                    if ui
+                        .add_enabled(!layout.is_unchanged(), egui::Button::new("Save"))
+                        .clicked()
+                    {
+                        self.action = Action::SaveChapters;
+                    }
+                });
+            });
+        }
+        if close_layout {
+            self.chapter_layout = None;
+        }
+
+        let mut close_split = false;
+        if let Some(dialog) = &mut self.split_dialog {
+            egui::Window::new("Move chapters to another tag")
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label("Chapters");
+                        ui.add(
+                            egui::DragValue::new(&mut dialog.first).clamp_range(1..=dialog.last),
+                        );
+                        ui.label("to");
+                        ui.add(
+                            egui::DragValue::new(&mut dialog.last)
+                                .clamp_range(dialog.first..=dialog.chapter_count),
+                        );
+                        ui.label(format!("of {}", dialog.chapter_count));
+                    });
+                    ui.checkbox(&mut dialog.remove, "Remove them from this Toniefile");
+                    ui.label("tag ID of the new Toniefile: ");
+                    ui.add(
+                        egui::TextEdit::singleline(&mut dialog.tag)
+                            .char_limit(16)
+                            .font(FontId::default()),
+                    );
+                    let valid = check_tag_id_validity(&dialog.tag);
+                    if let Err(e) = &valid {
+                        ui.label(e.to_string());
+                    }
+                    ui.horizontal(|ui| {
+                        if ui.button("Cancel").clicked() {
+                            close_split = true;
+                        }
+                        if ui
+                            .add_enabled(valid.is_ok(), egui::Button::new("Ok"))
+                            .clicked()
+                        {
+                            self.action = Action::SplitChapters;
+                        }
+                    });
+                });
+        }
+        if close_split {
+            self.split_dialog = None;
+        }
+
+        let mut close_merge = false;
+        if let Some(dialog) = &mut self.merge_dialog {
+            egui::Window::new(format!(
+                "Merge into {}",
+                dialog
+                    .dest
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+            ))
+            .collapsible(false)
+            .resizable(true)
+            .show(ctx, |ui| {
+                ui.label("The chapters of these Toniefiles are added in this order:");
+                egui::ScrollArea::vertical()
+                    .max_height(300.0)
+                    .show(ui, |ui| {
+                        for (_, tag, merge) in &mut dialog.sources {
+                            ui.checkbox(merge, RichText::new(tag.as_str()).monospace());
+                        }
+                    });
+                ui.checkbox(&mut dialog.remove, "Move them to the trash afterwards");
+                ui.horizontal(|ui| {
+                    if ui.button("Cancel").clicked() {
+                        close_merge = true;
+                    }
+                    if ui
+                        .add_enabled(
+                            dialog.sources.iter().any(|(_, _, merge)| *merge),
+                            egui::Button::new("Ok"),
+                        )
+                        .clicked()
+                    {
+                        self.action = Action::MergeFiles;
+                    }
+                });
+            });
+        }
+        if close_merge {
+            self.merge_dialog = None;
+        }
+
+        if let Some(groups) = &self.duplicates {
+            let mut open = true;
+            let mut action = None;
+            egui::Window::new("Duplicates")
+                .collapsible(false)
+                .resizable(true)
+                .open(&mut open)
+                .show(ctx, |ui| {
+                    if groups.is_empty() {
+                        ui.label("There are no duplicates on this card.");
+                    } else {
+                        ui.label(format!(
+                            "Deleting all but one copy would free {}",
+                            format_size(groups.iter().map(|g| g.wasted()).sum())
+                        ));
+                    }
+                    egui::ScrollArea::vertical()
+                        .max_height(400.0)
+                        .show(ui, |ui| {
+                            for (i, group) in groups.iter().enumerate() {
+                                ui.separator();
+                                ui.label(RichText::new(group.duplicate.to_string()).strong());
+                                egui::Grid::new(("duplicates", i))
+                                    .striped(true)
+                                    .show(ui, |ui| {
+                                        for file in &group.files {
+                                            ui.label(
+                                                RichText::new(self.format_tag_id(&file.tag))
+                                                    .monospace(),
+                                            );
+                                            ui.label(format_size(file.size));
+                                            ui.label(file.title.as_deref().unwrap_or("unknown"));
+                                            if ui.button("Delete").clicked() {
+                                                action = Some(Action::DeleteDuplicate(
+                                                    file.path.clone(),
+                                                ));
+                                            }
+                                            if ui.button("Change tag ID").clicked() {
+                                                action =
+                                                    Some(Action::RetagDuplicate(file.path.clone()));
+                                            }
+                                            ui.end_row();
+                                        }
+                                    });
+                            }
+                        });
+                });
+            if let Some(action) = action {
+                self.action = action;
+            }
+            if !open {
+                self.duplicates = None;
+            }
+        }
+
+        if let Some(report) = &self.diff_report {
+            let mut open = true;
+            egui::Window::new(format!("Compare with {}", report.right.display()))
+                .collapsible(false)
+                .resizable(true)
+                .open(&mut open)
+                .show(ctx, |ui| {
+                    ui.label(format!(
+                        "{} Toniefiles are the same in both folders",
+                        report.identical
+                    ));
+                    if report.is_empty() {
+                        ui.label("There are no differences.");
+                    }
+                    egui::ScrollArea::vertical()
+                        .max_height(400.0)
+                        .show(ui, |ui| {
+                            for (heading, files) in [
+                                ("Only on this card", &report.only_left),
+                                ("Only in the other folder", &report.only_right),
+                            ] {
+                                if files.is_empty() {
+                                    continue;
+                                }
+                                ui.separator();
+                                ui.label(RichText::new(heading).color(Color32::LIGHT_BLUE));
+                                for file in files {
+                                    ui.horizontal(|ui| {
+                                        ui.label(
+                                            RichText::new(self.format_tag_id(&file.tag))
+                                                .monospace(),
+                                        );
+                                        ui.label(file.title.as_deref().unwrap_or("unknown"));
+                                    });
+                                }
+                            }
+                            if !report.changed.is_empty() {
+                                ui.separator();
+                                ui.label(
+                                    RichText::new("Different audio for the same tag")
+                                        .color(Color32::LIGHT_RED),
+                                );
+                                for changed in &report.changed {
+                                    ui.label(
+                                        RichText::new(self.format_tag_id(&changed.tag)).monospace(),
+                                    );
+                                }
+                            }
+                            if !report.same_audio.is_empty() {
+                                ui.separator();
+                                ui.label(
+                                    RichText::new("Same audio under different tags")
+                                        .color(Color32::YELLOW),
+                                );
+                                for same in &report.same_audio {
+                                    ui.label(
+                                        RichText::new(format!(
+                                            "this card: {}  other folder: {}",
+                                            same.left_tags.join(", "),
+                                            same.right_tags.join(", ")
+                                        ))
+                                        .monospace(),
+                                    );
+                                }
+                            }
+                        });
+                });
+            if !open {
+                self.diff_report = None;
+            }
+        }
+
+        let mut close_sync = false;
+        if let Some(dialog) = &mut self.sync_dialog {
+            egui::Window::new(format!("Sync with {}", dialog.other.display()))
+                .collapsible(false)
+                .resizable(true)
+                .show(ctx, |ui| {
+                    if dialog.differences.is_empty() {
+                        ui.label("Both folders have the same Toniefiles.");
+                    }
+                    egui::ScrollArea::vertical()
+                        .max_height(400.0)
+                        .show(ui, |ui| {
+                            egui::Grid::new("sync").striped(true).show(ui, |ui| {
+                                for (difference, selected, direction) in &mut dialog.differences {
+                                    ui.checkbox(
+                                        selected,
+                                        difference.describe(
+                                            *direction,
+                                            "this card",
+                                            "the other folder",
+                                        ),
+                                    );
+                                    ui.radio_value(
+                                        direction,
+                                        Direction::LeftToRight,
+                                        "to the other folder",
+                                    );
+                                    ui.radio_value(
+                                        direction,
+                                        Direction::RightToLeft,
+                                        "to this card",
+                                    );
+                                    ui.end_row();
+                                }
+                            });
+                        });
+                    ui.horizontal(|ui| {
+                        if ui.button("Cancel").clicked() {
+                            close_sync = true;
+                        }
+                        if ui
+                            .add_enabled(
+                                dialog.differences.iter().any(|(_, selected, _)| *selected),
+                                egui::Button::new("Sync"),
+                            )
+                            .clicked()
+                        {
+                            self.action = Action::ApplySync;
+                        }
+                    });
+                });
+        }
+        if close_sync {
+            self.sync_dialog = None;
+        }
+
+        let mut close_swap = false;
+        if let Some(dialog) = &mut self.swap_dialog {
+            egui::Window::new(format!("Swap tag {}", dialog.tag))
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    ui.label("Exchange the Toniefiles of this tag and");
+                    egui::ComboBox::from_id_source("swap tag")
+                        .selected_text(
+                            RichText::new(dialog.others[dialog.other].1.as_str()).monospace(),
+                        )
+                        .show_ui(ui, |ui| {
+                            for (i, (_, tag)) in dialog.others.iter().enumerate() {
+                                ui.selectable_value(
+                                    &mut dialog.other,
+                                    i,
+                                    RichText::new(tag.as_str()).monospace(),
+                                );
+                            }
+                        });
+                    ui.horizontal(|ui| {
+                        if ui.button("Cancel").clicked() {
+                            close_swap = true;
+                        }
+                        if ui.button("Ok").clicked() {
+                            self.action = Action::SwapTags;
+                        }
+                    });
+                });
+        }
+        if close_swap {
+            self.swap_dialog = None;
+        }
+
+        if let Some(usage) = &self.card_usage {
+            let mut open = true;
+            egui::Window::new("Card capacity")
+                .collapsible(false)
+                .resizable(true)
+                .open(&mut open)
+                .show(ctx, |ui| {
+                    ui.add(
+                        egui::ProgressBar::new(usage.used() as f32 / usage.total.max(1) as f32)
+                            .text(format!(
+                                "{} of {} used, {} free",
+                                format_size(usage.used()),
+                                format_size(usage.total),
+                                format_size(usage.available)
+                            )),
+                    );
+                    ui.label(format!(
+                        "{} Toniefiles take {}",
+                        usage.toniefile_count,
+                        format_size(usage.toniefiles)
+                    ));
+                    ui.separator();
+                    ui.label("The largest Toniefiles:");
+                    egui::Grid::new("largest_files")
+                        .striped(true)
+                        .show(ui, |ui| {
+                            for file in &usage.largest {
+                                ui.label(RichText::new(self.format_tag_id(&file.tag)).monospace());
+                                ui.label(format_size(file.size));
+                                ui.label(file.title.as_deref().unwrap_or("unknown"));
+                                ui.end_row();
+                            }
+                        });
+                });
+            if !open {
+                self.card_usage = None;
+            }
+        }
+
+        if let Some(findings) = &self.health {
+            let mut open = true;
+            let mut action = None;
+            egui::Window::new("Health check")
+                .collapsible(false)
+                .resizable(true)
+                .open(&mut open)
+                .show(ctx, |ui| {
+                    if findings.is_empty() {
+                        ui.label(
+                            "Everything in this CONTENT folder is a Toniefile in a tag folder.",
+                        );
+                    }
+                    egui::ScrollArea::vertical()
+                        .max_height(400.0)
+                        .show(ui, |ui| {
+                            for problem in Problem::ALL {
+                                let paths: Vec<_> = findings
+                                    .iter()
+                                    .filter(|f| f.problem == problem)
+                                    .map(|f| {
+                                        f.path.strip_prefix(&self.picked_path).unwrap_or(&f.path)
+                                    })
+                                    .collect();
+                                if paths.is_empty() {
+                                    continue;
+                                }
+                                ui.separator();
+                                ui.label(
+                                    RichText::new(format!("{} ({})", problem, paths.len()))
+                                        .strong(),
+                                );
+                                for path in paths {
+                                    ui.label(RichText::new(path.display().to_string()).monospace());
+                                }
+                                // the temporary files may belong to a Toniefile that is encoded
+                                let busy = problem == Problem::TempLeftover && self.jobs.is_busy();
+                                if ui
+                                    .add_enabled(!busy, egui::Button::new("Repair"))
+                                    .on_hover_text(problem.repair_description())
+                                    .on_disabled_hover_text("Wait until all jobs are finished")
+                                    .clicked()
+                                {
+                                    action = Some(Action::RepairHealth(problem));
+                                }
+                            }
+                        });
+                });
+            if let Some(action) = action {
+                self.action = action;
+            }
+            if !open {
+                self.health = None;
+            }
+        }
+
+        if let Some(entries) = &self.trash {
+            let mut open = true;
+            let mut action = None;
+            egui::Window::new("Trash")
+                .collapsible(false)
+                .resizable(true)
+                .open(&mut open)
+                .show(ctx, |ui| {
+                    ui.label(format!(
+                        "{} files take {}",
+                        entries.len(),
+                        format_size(entries.iter().map(|e| e.size).sum())
+                    ));
+                    egui::ScrollArea::vertical()
+                        .max_height(300.0)
+                        .show(ui, |ui| {
+                            egui::Grid::new("trash").striped(true).show(ui, |ui| {
+                                for (i, entry) in entries.iter().enumerate() {
+                                    // files that were not Toniefiles, from a health check repair
+                                    let name = match entry.tag.as_str() {
+                                        "invalid" => entry.origin.display().to_string(),
+                                        tag => self.format_tag_id(tag),
+                                    };
+                                    ui.label(RichText::new(name).monospace());
+                                    ui.label(format_size(entry.size));
+                                    ui.label(entry.age());
+                                    if ui.button("Restore").clicked() {
+                                        action = Some(Action::RestoreFromTrash(i));
+                                    }
+                                    ui.end_row();
+                                }
+                            });
+                        });
+                    if ui
+                        .add_enabled(!entries.is_empty(), egui::Button::new("Empty trash"))
+                        .clicked()
+                    {
+                        action = Some(Action::EmptyTrash);
+                    }
+                });
+            if let Some(action) = action {
+                self.action = action;
+            }
+            if !open {
+                self.trash = None;
+            }
+        }
+
+        let mut close_audio_id = false;
+        if let Some(dialog) = &mut self.audio_id_dialog {
+            egui::Window::new("Change audio ID")
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    ui.label(format!("current audio ID: {}", dialog.current));
+                    let valid = dialog.choice.show(ui, &self.tonies);
+                    ui.horizontal(|ui| {
+                        if ui.button("Cancel").clicked() {
+                            close_audio_id = true;
+                        }
+                        if ui.add_enabled(valid, egui::Button::new("Ok")).clicked() {
+                            self.action = Action::SetAudioId;
+                        }
+                    });
+                });
+        }
+        if close_audio_id {
+            self.audio_id_dialog = None;
+        }
+
         if self.error.is_some() {
             egui::Window::new("Error")
                 .collapsible(false)
@@ -426,6 +1704,29 @@ impl eframe::App for RustyBench {
                     });
                 });
         }
+        if self.decode_report.is_some() {
+            egui::Window::new("Broken packets were skipped")
+                .collapsible(false)
+                .resizable(true)
+                .show(ctx, |ui| {
+                    if let Some(report) = &self.decode_report {
+                        ui.label(format!(
+                            "Some packets of the input files could not be decoded, the Toniefile will have gaps.\nPolicy: {}",
+                            report.policy
+                        ));
+                        egui::ScrollArea::vertical()
+                            .max_height(300.0)
+                            .show(ui, |ui| {
+                                ui.label(RichText::new(report.to_string()).monospace());
+                            });
+                    }
+                    ui.horizontal(|ui| {
+                        if ui.button("Ok").clicked() {
+                            self.decode_report = None;
+                        }
+                    });
+                });
+        }
         // NOTE unwrapping self.selection is ok below here, because the buttons are disabled if
         // self.selection is None
         let thr = thread::Builder::new().name("action_thread".to_string());
@@ -435,9 +1736,225 @@ impl eframe::App for RustyBench {
             Action::AskAddAudioFile => {
                 self.action = Action::None;
                 if let Some(files) = rfd::FileDialog::new().pick_files() {
-                    self.picked_files = files;
-                    self.show_id_popup = true;
-                    self.action = Action::AddAudioFile;
+                    // playlists and CUE sheets are expanded into their chapters
+                    self.show_inputs(expand_inputs(&files));
+                }
+            }
+            Action::AskAppendChapters => {
+                self.action = Action::None;
+                if let Some(files) = rfd::FileDialog::new().pick_files() {
+                    let path = self.files[self.selection.unwrap()].path.clone();
+                    self.chapter_target = Some(JobTarget::Append(path));
+                    self.show_inputs(expand_inputs(&files));
+                }
+            }
+            Action::AskReencode => {
+                self.action = Action::None;
+                let file = &self.files[self.selection.unwrap()];
+                match check_tag_id_validity(&file.tag) {
+                    Ok(()) => {
+                        self.chapter_target = Some(JobTarget::Tag {
+                            content: self.picked_path.clone(),
+                            tag: file.tag.clone(),
+                        });
+                        let inputs = toniefile_inputs(&file.path);
+                        self.show_inputs(inputs);
+                    }
+                    Err(e) => self.error = Some(e),
+                }
+            }
+            Action::EditChapters => {
+                self.action = Action::None;
+                match ChapterLayout::load(&self.files[self.selection.unwrap()].path) {
+                    Ok(layout) => self.chapter_layout = Some(layout),
+                    Err(e) => self.error = Some(e),
+                }
+            }
+            Action::SaveChapters => {
+                self.action = Action::PopulateTable;
+                if let Some(layout) = self.chapter_layout.take() {
+                    if let Err(e) = layout.save() {
+                        self.error = Some(e);
+                    }
+                }
+            }
+            Action::SplitChapters => {
+                self.action = Action::PopulateTable;
+                if let Some(dialog) = self.split_dialog.take() {
+                    if let Err(e) = split_chapters(
+                        &dialog.path,
+                        dialog.first - 1..dialog.last,
+                        &self.picked_path,
+                        &dialog.tag,
+                        dialog.remove,
+                        &self.tonies,
+                    ) {
+                        self.error = Some(e);
+                    }
+                }
+            }
+            Action::MergeFiles => {
+                self.action = Action::PopulateTable;
+                if let Some(dialog) = self.merge_dialog.take() {
+                    let mut sources = vec![dialog.dest.clone()];
+                    sources.extend(
+                        dialog
+                            .sources
+                            .into_iter()
+                            .filter(|(_, _, merge)| *merge)
+                            .map(|(path, _, _)| path),
+                    );
+                    let result = merge_files(
+                        &sources,
+                        &dialog.dest,
+                        &self.picked_path,
+                        dialog.remove,
+                        &self.tonies,
+                    );
+                    match result {
+                        Ok(removed) => self
+                            .undo_history
+                            .extend(removed.into_iter().map(UndoStep::Delete)),
+                        Err(e) => {
+                            // the sources that did get to the trash can be restored
+                            if let Some(left) = e.downcast_ref::<SourcesLeft>() {
+                                let removed = left.removed.iter().cloned();
+                                self.undo_history.extend(removed.map(UndoStep::Delete));
+                            }
+                            self.error = Some(e);
+                        }
+                    }
+                }
+            }
+            Action::SetAudioId => {
+                self.action = Action::PopulateTable;
+                if let Some(dialog) = self.audio_id_dialog.take() {
+                    let result = dialog
+                        .choice
+                        .audio_id
+                        .resolve(&self.tonies)
+                        .and_then(|audio_id| set_audio_id(&dialog.path, audio_id));
+                    if let Err(e) = result {
+                        self.error = Some(e);
+                    }
+                }
+            }
+            Action::SwapTags => {
+                self.action = Action::PopulateTable;
+                if let Some(dialog) = self.swap_dialog.take() {
+                    let (other, _) = &dialog.others[dialog.other];
+                    match swap_toniefiles(&dialog.path, other) {
+                        Ok(_) => self
+                            .undo_history
+                            .push(UndoStep::Swap(dialog.path, other.clone())),
+                        Err(e) => self.error = Some(e),
+                    }
+                }
+            }
+            Action::Undo => {
+                self.action = Action::PopulateTable;
+                if let Some(step) = self.undo_history.pop() {
+                    info!("undoing {}", step);
+                    step.undo(&self.picked_path)
+                        .unwrap_or_else(|e| self.error = Some(e));
+                }
+            }
+            Action::ShowTrash => {
+                self.action = Action::None;
+                match list_trash(&self.picked_path) {
+                    Ok(entries) => self.trash = Some(entries),
+                    Err(e) => self.error = Some(e),
+                }
+            }
+            Action::RestoreFromTrash(i) => {
+                self.action = Action::ShowTrash;
+                if let Some(entry) = self.trash.as_ref().and_then(|t| t.get(i)).cloned() {
+                    let mut result = restore(&self.picked_path, &entry, false);
+                    if let Some(TagInUse(tag)) =
+                        result.as_ref().err().and_then(|e| e.downcast_ref())
+                    {
+                        if rfd::MessageDialog::new()
+                            .set_description(format!(
+                                "There already is a Toniefile for tag {}. Do you want to move it to the trash and restore this one?",
+                                self.format_tag_id(tag)
+                            ))
+                            .set_buttons(rfd::MessageButtons::YesNo)
+                            .show()
+                            == rfd::MessageDialogResult::Yes
+                        {
+                            result = restore(&self.picked_path, &entry, true);
+                        } else {
+                            result = Ok(None);
+                        }
+                    }
+                    if let Err(e) = result {
+                        self.error = Some(e);
+                    }
+                    self.reload_files();
+                }
+            }
+            Action::EmptyTrash => {
+                self.action = Action::ShowTrash;
+                if rfd::MessageDialog::new()
+                    .set_description(
+                        "Do you really want to delete all files in the trash for good?",
+                    )
+                    .set_buttons(rfd::MessageButtons::YesNo)
+                    .show()
+                    == rfd::MessageDialogResult::Yes
+                {
+                    empty_trash(&self.picked_path).unwrap_or_else(|e| self.error = Some(e));
+                    // the Toniefiles of these steps are gone, a changed tag ID can still be
+                    // changed back, but the Toniefile it replaced can not be restored
+                    self.undo_history
+                        .retain(|step| !matches!(step, UndoStep::Delete(_)));
+                    for step in &mut self.undo_history {
+                        if let UndoStep::ChangeTagId { replaced, .. } = step {
+                            *replaced = None;
+                        }
+                    }
+                }
+            }
+            Action::ShowCapacity => {
+                self.action = Action::None;
+                match card_usage(&self.picked_path, &self.tonies) {
+                    Ok(usage) => self.card_usage = Some(usage),
+                    Err(e) => self.error = Some(e),
+                }
+            }
+            Action::AskAddFolder => {
+                self.action = Action::None;
+                if let Some(folder) = rfd::FileDialog::new().pick_folder() {
+                    self.show_inputs(collect_folder(&folder));
+                }
+            }
+            Action::AskBuild => {
+                self.action = Action::None;
+                if let Some(path) = rfd::FileDialog::new()
+                    .add_filter("Manifest", &["toml", "json"])
+                    .pick_file()
+                {
+                    let steps = Manifest::load(&path).and_then(|manifest| {
+                        plan_build(
+                            &manifest,
+                            &self.picked_path,
+                            self.encode_options.policy,
+                            false,
+                        )
+                    });
+                    match steps {
+                        Ok(steps) => {
+                            for mut step in steps {
+                                if step.action == BuildAction::Skip {
+                                    info!("{} is up to date", step.request.target);
+                                } else {
+                                    step.request.tonies = self.tonies.clone();
+                                    self.jobs.enqueue(step.request);
+                                }
+                            }
+                        }
+                        Err(e) => self.error = Some(e),
+                    }
                 }
             }
             Action::AddAudioFile => {
@@ -445,17 +1962,43 @@ impl eframe::App for RustyBench {
                     self.action = Action::None;
                     info!("adding audio file");
                     self.tag_id_valid = false;
-                    let tag = self.tag_id.clone();
-                    let path = self.picked_path.clone();
-                    let files = self.picked_files.clone();
-                    let add_audio_tx = self.thread_sender.clone();
-                    let jh = thr
-                        .spawn(move || add_audio_file(path, files, tag, add_audio_tx))
-                        .unwrap();
-                    self.joinhandles.push(jh);
+                    self.jobs.enqueue(JobRequest {
+                        target: JobTarget::Tag {
+                            content: self.picked_path.clone(),
+                            tag: self.tag_id.clone(),
+                        },
+                        chapters: std::mem::take(&mut self.picked_chapters),
+                        options: EncodeOptions {
+                            audio_id: std::mem::take(&mut self.audio_id_choice).audio_id,
+                            ..self.encode_options.clone()
+                        },
+                        fingerprint: None,
+                        tonies: self.tonies.clone(),
+                    });
                     self.tag_id = "E0040350".to_string();
                 }
             }
+            Action::EncodeChapters => {
+                self.action = Action::None;
+                if let Some(target) = self.chapter_target.take() {
+                    info!("encoding chapters: {}", target);
+                    let mut options = self.encode_options.clone();
+                    if let JobTarget::Tag { content, tag } = &target {
+                        // a re-encoded Toniefile keeps its audio ID, the tag ID was checked when the
+                        // chapters were picked
+                        if let Ok(path) = tag_path(content, tag) {
+                            options.audio_id = AudioId::CopyFrom(path);
+                        }
+                    }
+                    self.jobs.enqueue(JobRequest {
+                        target,
+                        chapters: std::mem::take(&mut self.picked_chapters),
+                        options,
+                        fingerprint: None,
+                        tonies: self.tonies.clone(),
+                    });
+                }
+            }
             Action::AskChangeTagId => {
                 self.show_id_popup = true;
                 self.action = Action::ChangeTagId;
@@ -465,30 +2008,66 @@ impl eframe::App for RustyBench {
                     self.action = Action::None;
                     info!("changing tag id");
                     self.tag_id_valid = false;
-                    change_tag_id(
-                        &self.picked_path,
-                        &self.files[self.selection.unwrap()],
-                        &self.tag_id,
-                    )
-                    .unwrap_or_else(|e| self.error = Some(e));
+                    let file = &self.files[self.selection.unwrap()];
+                    let (path, old_tag) = (&file.path, &file.tag);
+                    let mut result = change_tag_id(&self.picked_path, path, &self.tag_id, false);
+                    // nothing is moved if the user keeps the Toniefile that has the tag ID
+                    let mut moved = true;
+                    if let Some(TagInUse(tag)) =
+                        result.as_ref().err().and_then(|e| e.downcast_ref())
+                    {
+                        if rfd::MessageDialog::new()
+                            .set_description(format!(
+                                "There already is a Toniefile for tag {}. Do you want to replace it?",
+                                self.format_tag_id(tag)
+                            ))
+                            .set_buttons(rfd::MessageButtons::YesNo)
+                            .show()
+                            == rfd::MessageDialogResult::Yes
+                        {
+                            result = change_tag_id(&self.picked_path, path, &self.tag_id, true);
+                        } else {
+                            moved = false;
+                            result = Ok(None);
+                        }
+                    }
+                    match result {
+                        // a Toniefile in a folder that is no tag ID can not be moved back
+                        Ok(replaced) if moved && check_tag_id_validity(old_tag).is_ok() => {
+                            // the new tag ID is valid, otherwise the file would not have moved
+                            if let Ok(path) = tag_path(&self.picked_path, &self.tag_id) {
+                                self.undo_history.push(UndoStep::ChangeTagId {
+                                    path,
+                                    tag: old_tag.clone(),
+                                    replaced,
+                                })
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(e) => self.error = Some(e),
+                    }
                     self.action = Action::PopulateTable;
                     self.tag_id = "E0040350".to_string();
                 }
             }
             Action::PopulateTable => {
                 info!("populating table");
-                self.processed = 0;
                 self.action = Action::None;
-                self.selection = None;
-                self.files.clear();
-                populate_table(&self.picked_path, &mut self.files, &self.tonies)
-                    .unwrap_or_else(|e| self.error = Some(e));
+                self.reload_files();
+                if self.duplicates.is_some() {
+                    self.action = Action::ShowDuplicates;
+                }
             }
             Action::ExtractToOgg => {
                 info!("extracting to ogg");
                 self.action = Action::None;
-                if let Some(path) = rfd::FileDialog::new().set_file_name(".ogg").save_file() {
-                    let sel = self.files[self.selection.unwrap()].clone();
+                let sel = self.files[self.selection.unwrap()].clone();
+                // system files without audio are saved as they are
+                let name = match sel.is_system() && !sel.is_valid {
+                    true => sel.path.file_name().unwrap_or_default().to_string_lossy(),
+                    false => ".ogg".into(),
+                };
+                if let Some(path) = rfd::FileDialog::new().set_file_name(name).save_file() {
                     let add_audio_tx = self.thread_sender.clone();
                     let jh = thr
                         .spawn(move || extract_to_ogg(&sel, &path, add_audio_tx))
@@ -508,6 +2087,132 @@ impl eframe::App for RustyBench {
                     self.joinhandles.push(jh);
                 }
             }
+            Action::Backup => {
+                self.action = Action::None;
+                if let Some(archive) = rfd::FileDialog::new()
+                    .add_filter("backup", &["gz"])
+                    .set_file_name("toniebox-backup.tar.gz")
+                    .save_file()
+                {
+                    let content = self.picked_path.clone();
+                    let status_tx = self.thread_sender.clone();
+                    let jh = thr
+                        .spawn(move || backup(&content, &archive, &status_tx).map(|_| ()))
+                        .unwrap();
+                    self.joinhandles.push(jh);
+                }
+            }
+            Action::RestoreBackup => {
+                self.action = Action::None;
+                let archive = rfd::FileDialog::new()
+                    .add_filter("backup", &["gz"])
+                    .pick_file();
+                let card = archive.as_ref().and_then(|_| {
+                    rfd::FileDialog::new()
+                        .set_title("Root folder of the SD card")
+                        .pick_folder()
+                });
+                if let (Some(archive), Some(card)) = (archive, card) {
+                    let content = card.join("CONTENT");
+                    self.picked_path = content.clone();
+                    self.undo_history.clear();
+                    let status_tx = self.thread_sender.clone();
+                    let jh = thr
+                        .spawn(move || {
+                            let result = restore_backup(&archive, &content, false, &status_tx);
+                            status_tx.send(Action::PopulateTable)?;
+                            result.map(|_| ())
+                        })
+                        .unwrap();
+                    self.joinhandles.push(jh);
+                }
+            }
+            Action::ShowDuplicates => {
+                self.action = Action::None;
+                match find_duplicates(&self.picked_path, &self.tonies) {
+                    Ok(groups) => self.duplicates = Some(groups),
+                    Err(e) => self.error = Some(e),
+                }
+            }
+            Action::ShowHealth => {
+                self.action = Action::None;
+                match check_health(&self.picked_path) {
+                    Ok(findings) => self.health = Some(findings),
+                    Err(e) => self.error = Some(e),
+                }
+            }
+            Action::RepairHealth(problem) => {
+                self.action = Action::ShowHealth;
+                if let Some(findings) = &self.health {
+                    repair_all(&self.picked_path, findings, &[problem])
+                        .unwrap_or_else(|e| self.error = Some(e));
+                }
+                self.reload_files();
+            }
+            Action::DeleteDuplicate(ref path) | Action::RetagDuplicate(ref path) => {
+                let next = match self.action {
+                    Action::DeleteDuplicate(_) => Action::DeleteFile,
+                    _ => Action::AskChangeTagId,
+                };
+                self.selection = self.files.iter().position(|f| &f.path == path);
+                self.action = match self.selection {
+                    Some(_) => next,
+                    None => Action::PopulateTable,
+                };
+            }
+            Action::AskCompare => {
+                self.action = Action::None;
+                if let Some(other) = rfd::FileDialog::new()
+                    .set_title("CONTENT folder to compare with")
+                    .pick_folder()
+                {
+                    match diff_report(&self.picked_path, &other, &self.tonies) {
+                        Ok(report) => self.diff_report = Some(report),
+                        Err(e) => self.error = Some(e),
+                    }
+                }
+            }
+            Action::AskSync => {
+                self.action = Action::None;
+                if let Some(other) = rfd::FileDialog::new()
+                    .set_title("CONTENT folder to sync with")
+                    .pick_folder()
+                {
+                    match compare(&self.picked_path, &other) {
+                        Ok(differences) => {
+                            self.sync_dialog = Some(SyncDialog {
+                                differences: differences
+                                    .into_iter()
+                                    .map(|d| (d, false, Direction::LeftToRight))
+                                    .collect(),
+                                other,
+                            })
+                        }
+                        Err(e) => self.error = Some(e),
+                    }
+                }
+            }
+            Action::ApplySync => {
+                self.action = Action::None;
+                if let Some(dialog) = self.sync_dialog.take() {
+                    let changes: Vec<_> = dialog
+                        .differences
+                        .into_iter()
+                        .filter(|(_, selected, _)| *selected)
+                        .map(|(difference, _, direction)| (difference, direction))
+                        .collect();
+                    let content = self.picked_path.clone();
+                    let status_tx = self.thread_sender.clone();
+                    let jh = thr
+                        .spawn(move || {
+                            let result = apply_all(&content, &dialog.other, &changes, &status_tx);
+                            status_tx.send(Action::PopulateTable)?;
+                            result
+                        })
+                        .unwrap();
+                    self.joinhandles.push(jh);
+                }
+            }
             Action::PlayFile => {
                 info!("playing file");
                 self.action = Action::None;
@@ -519,13 +2224,15 @@ impl eframe::App for RustyBench {
             Action::DeleteFile => {
                 info!("deleting file");
                 if rfd::MessageDialog::new()
-                    .set_description("Do you really want to delete this file?")
+                    .set_description("Do you really want to move this file to the trash?")
                     .set_buttons(rfd::MessageButtons::YesNo)
                     .show()
                     == rfd::MessageDialogResult::Yes
                 {
-                    delete_file(&self.files[self.selection.unwrap()])
-                        .unwrap_or_else(|e| self.error = Some(e));
+                    match delete_file(&self.picked_path, &self.files[self.selection.unwrap()]) {
+                        Ok(entry) => self.undo_history.push(UndoStep::Delete(entry)),
+                        Err(e) => self.error = Some(e),
+                    }
                 }
                 self.action = Action::PopulateTable;
             }
@@ -546,22 +2253,18 @@ impl eframe::App for RustyBench {
                     .set_buttons(rfd::MessageButtons::Ok)
                     .show();
             }
-            Action::Processing(_) => {}
-            Action::CurrentFileNo(_) => {}
+            Action::Job(..) => {}
             Action::CurrentFile(_) => {}
         }
-        if let Ok(action) = self.thread_receiver.try_recv() {
+        while let Ok(action) = self.thread_receiver.try_recv() {
             info!("recvd thread action: {:?}", action);
             ctx.request_repaint();
             match action {
                 Action::PopulateTable => {
                     self.action = Action::PopulateTable;
                 }
-                Action::Processing(p) => {
-                    self.processed = p;
-                }
-                Action::CurrentFileNo(n) => {
-                    self.current_fileno = n;
+                Action::Job(id, event) => {
+                    self.jobs.handle_event(id, event);
                 }
                 Action::CurrentFile(f) => {
                     self.current_file = f;
@@ -569,6 +2272,17 @@ impl eframe::App for RustyBench {
                 _ => {}
             }
         }
+        if let Some(job) = self.jobs.poll(&self.thread_sender) {
+            info!("job {} finished: {}", job.id, job.state);
+            self.action = Action::PopulateTable;
+            if job.report.is_some() {
+                self.decode_report = job.report;
+            }
+        }
+        if self.jobs.is_busy() || !self.joinhandles.is_empty() {
+            // keep polling the queue and the threads while there is something to do
+            ctx.request_repaint_after(std::time::Duration::from_millis(200));
+        }
         // join any finished threads
         for i in 0..self.joinhandles.len() {
             if self.joinhandles[i].is_finished() {